@@ -0,0 +1,67 @@
+use crate::{cache::Disposition, client_ip::ClientIp};
+use poem::{Endpoint, FromRequest, IntoResponse, Middleware, Request, Response, Result};
+use std::time::Instant;
+use tracing::{info, info_span, Instrument};
+
+/// Logs every request once it has been answered, along with how the cache served it
+///
+/// Has to be inside of the [`TrustProxy`](crate::client_ip::TrustProxy) data to log the right
+/// client address.
+pub struct AccessLog;
+
+impl<E: Endpoint> Middleware<E> for AccessLog {
+    type Output = AccessLogEndpoint<E>;
+
+    fn transform(&self, inner: E) -> Self::Output {
+        AccessLogEndpoint { inner }
+    }
+}
+
+pub struct AccessLogEndpoint<E> {
+    inner: E,
+}
+
+#[poem::async_trait]
+impl<E: Endpoint> Endpoint for AccessLogEndpoint<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        let client = ClientIp::from_request_without_body(&req)
+            .await
+            .unwrap_or(ClientIp(None));
+        let method = req.method().clone();
+        let path = req.original_uri().path().to_owned();
+
+        let span = info_span!("request", %client, %method, %path);
+
+        async move {
+            let start = Instant::now();
+            let result = self.inner.call(req).await;
+            let latency = start.elapsed();
+
+            match result {
+                Ok(response) => {
+                    let response = response.into_response();
+                    let cache = response
+                        .extensions()
+                        .get::<Disposition>()
+                        .map(|disposition| disposition.as_str());
+
+                    info!(
+                        status = response.status().as_u16(),
+                        ?latency,
+                        cache,
+                        "response"
+                    );
+                    Ok(response)
+                }
+                Err(e) => {
+                    info!(status = e.status().as_u16(), ?latency, error = %e, "response");
+                    Err(e)
+                }
+            }
+        }
+        .instrument(span)
+        .await
+    }
+}
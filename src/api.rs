@@ -1,36 +1,1129 @@
-use reqwest::Response;
+use crate::{
+    config::{self, ConfigError},
+    error::Error,
+};
+use bytes::Bytes;
+use futures_util::{stream::BoxStream, StreamExt};
+use governor::{
+    clock::{Clock, DefaultClock},
+    DefaultDirectRateLimiter, Quota, RateLimiter,
+};
+use reqwest::{header, Certificate, NoProxy, Proxy, RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    num::NonZeroU32,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore};
+use tracing::{info, warn};
 
-const URL_AUTH: &str = "https://www.space-track.org/ajaxauth/login";
+const DEFAULT_BASE_URL: &str = "https://www.space-track.org";
+const AUTH_PATH: &str = "/ajaxauth/login";
+// Sessions live much longer than this, so a login this recent is still good
+const REAUTH_THROTTLE: Duration = Duration::from_secs(300);
+// A session this fresh that gets turned away points at Space-Track rather than the session, e.g.
+// during maintenance, so logging in again right away wouldn't help
+const REAUTH_COOLDOWN: Duration = Duration::from_secs(60);
+const DEFAULT_SLOW_QUERY_THRESHOLD: Duration = Duration::from_secs(2);
+
+// Space-Track suspends accounts that go beyond these
+const DEFAULT_REQUESTS_PER_MINUTE: NonZeroU32 = match NonZeroU32::new(30) {
+    Some(limit) => limit,
+    None => unreachable!(),
+};
+const DEFAULT_REQUESTS_PER_HOUR: NonZeroU32 = match NonZeroU32::new(300) {
+    Some(limit) => limit,
+    None => unreachable!(),
+};
+// Callers that would have to wait longer than this are told to come back later instead
+const MAX_THROTTLE_WAIT: Duration = Duration::from_secs(10);
+// Used when Space-Track answers with a 429 but doesn't say for how long
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+const DEFAULT_RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(8);
+
+// Everything goes to a single host, so a handful of warm connections covers concurrent misses
+const DEFAULT_POOL_MAX_IDLE: usize = 8;
+const DEFAULT_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+// Space-Track doesn't like many parallel connections from the same account
+const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 4;
+
+// A hung connection would otherwise hold up everyone waiting on the same fetch
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub struct SpaceTrackClient {
+    /// Shared by every query and login, its pool is what keeps connections to Space-Track open
     client: reqwest::Client,
+    // Overridable so the client can be pointed at a mock of Space-Track
+    base_url: String,
     user: String,
     pass: String,
+    slow_query_threshold: Duration,
+    /// Locked for the duration of a login so only one runs at a time
+    logins: Mutex<Logins>,
+    // Every request to Space-Track has to get through both of these
+    limiters: [DefaultDirectRateLimiter; 2],
+    /// Permits for requests that are underway, logins included
+    concurrency: Arc<Semaphore>,
+    backoff: Backoff,
+}
+
+/// How the most recent logins went, shared by every query that runs into an expired session
+#[derive(Default)]
+struct Logins {
+    /// Time of the last successful login
+    succeeded: Option<Instant>,
+    /// Time and outcome of the last failed login, handed to anyone who tries again too soon
+    failed: Option<(Instant, Error)>,
+}
+
+/// Response that holds on to its concurrency permit until it is dropped
+struct InFlight {
+    response: Response,
+    _permit: OwnedSemaphorePermit,
+}
+
+/// How often and how quickly failed queries are tried again
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    /// Tries per query including the first one
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubling with every further one
+    pub base_delay: Duration,
+}
+
+impl Backoff {
+    /// Randomized delay before the given retry so concurrent queries don't retry in lockstep
+    fn delay(&self, retry: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(1 << retry.min(16))
+            .min(MAX_RETRY_DELAY);
+
+        // Half of the delay is fixed and the other half random
+        let random = RandomState::new().build_hasher().finish();
+        delay / 2 + delay.mul_f64((random as f64 / u64::MAX as f64) / 2.0)
+    }
 }
 
 impl SpaceTrackClient {
-    pub fn from_env() -> Self {
-        let client = reqwest::Client::new();
-        let user =
-            std::env::var("SPACETRACK_USER").expect("missing user (env var SPACETRACK_USER)");
-        let pass =
-            std::env::var("SPACETRACK_PASS").expect("missing password (env var SPACETRACK_PASS)");
-
-        Self { client, user, pass }
-    }
-
-    pub async fn query(&self, query: String) -> Result<Response, reqwest::Error> {
-        let params = [
-            ("identity", &self.user),
-            ("password", &self.pass),
-            ("query", &query),
-        ];
-
-        self.client
-            .post(URL_AUTH)
-            .form(&params)
-            .send()
-            .await?
-            .error_for_status()
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let pool_max_idle =
+            config::env_var("GPCACHE_POOL_MAX_IDLE")?.unwrap_or(DEFAULT_POOL_MAX_IDLE);
+        let pool_idle_timeout = config::env_secs("GPCACHE_POOL_IDLE_TIMEOUT_SECS")?
+            .unwrap_or(DEFAULT_POOL_IDLE_TIMEOUT);
+
+        let connect_timeout =
+            config::env_secs("GPCACHE_CONNECT_TIMEOUT_SECS")?.unwrap_or(DEFAULT_CONNECT_TIMEOUT);
+        let request_timeout =
+            config::env_secs("GPCACHE_REQUEST_TIMEOUT_SECS")?.unwrap_or(DEFAULT_REQUEST_TIMEOUT);
+
+        let mut builder = reqwest::Client::builder();
+        for proxy in proxies()? {
+            builder = builder.proxy(proxy);
+        }
+        if let Some(ca) = ca_bundle()? {
+            builder = builder.add_root_certificate(ca);
+        }
+
+        let client = builder
+            .user_agent(user_agent()?)
+            .pool_max_idle_per_host(pool_max_idle)
+            .pool_idle_timeout(pool_idle_timeout)
+            .connect_timeout(connect_timeout)
+            .timeout(request_timeout)
+            // Holds the session cookie handed out by the login endpoint
+            .cookie_store(true)
+            .build()
+            .map_err(|e| ConfigError(format!("Failed to set up the HTTP client: {e}")))?;
+        let base_url = std::env::var("SPACETRACK_URL").unwrap_or_else(|_| DEFAULT_BASE_URL.into());
+        let (Ok(user), Ok(pass)) = (
+            std::env::var("SPACETRACK_USER"),
+            std::env::var("SPACETRACK_PASS"),
+        ) else {
+            return Err(ConfigError(
+                "Set SPACETRACK_USER and SPACETRACK_PASS to the Space-Track login".into(),
+            ));
+        };
+
+        let slow_query_threshold =
+            config::env_millis("GPCACHE_SLOW_QUERY_MS")?.unwrap_or(DEFAULT_SLOW_QUERY_THRESHOLD);
+
+        let per_minute = config::env_var("GPCACHE_UPSTREAM_REQUESTS_PER_MINUTE")?
+            .unwrap_or(DEFAULT_REQUESTS_PER_MINUTE);
+        let per_hour = config::env_var("GPCACHE_UPSTREAM_REQUESTS_PER_HOUR")?
+            .unwrap_or(DEFAULT_REQUESTS_PER_HOUR);
+
+        let max_concurrent = config::env_var("GPCACHE_UPSTREAM_MAX_CONCURRENT_REQUESTS")?
+            .unwrap_or(DEFAULT_MAX_CONCURRENT_REQUESTS)
+            .max(1);
+
+        let backoff = Backoff {
+            max_attempts: config::env_var("GPCACHE_UPSTREAM_MAX_ATTEMPTS")?
+                .unwrap_or(DEFAULT_MAX_ATTEMPTS)
+                .max(1),
+            base_delay: config::env_millis("GPCACHE_UPSTREAM_RETRY_BASE_MS")?
+                .unwrap_or(DEFAULT_RETRY_BASE_DELAY),
+        };
+
+        Ok(Self {
+            slow_query_threshold,
+            limiters: [
+                RateLimiter::direct(Quota::per_minute(per_minute)),
+                RateLimiter::direct(Quota::per_hour(per_hour)),
+            ],
+            concurrency: Arc::new(Semaphore::new(max_concurrent)),
+            ..Self::with_client(client, &base_url, user, pass).with_backoff(backoff)
+        })
+    }
+
+    /// Client for the Space-Track instance at `base_url` with the default settings, for tests
+    /// against a mock of Space-Track
+    #[cfg(test)]
+    pub fn new(base_url: &str, user: String, pass: String) -> Result<Self, ConfigError> {
+        let client = reqwest::Client::builder()
+            .user_agent(user_agent()?)
+            .cookie_store(true)
+            .build()
+            .map_err(|e| ConfigError(format!("Failed to set up the HTTP client: {e}")))?;
+
+        Ok(Self::with_client(client, base_url, user, pass))
+    }
+
+    fn with_client(client: reqwest::Client, base_url: &str, user: String, pass: String) -> Self {
+        Self {
+            client,
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            user,
+            pass,
+            slow_query_threshold: DEFAULT_SLOW_QUERY_THRESHOLD,
+            logins: Mutex::default(),
+            limiters: [
+                RateLimiter::direct(Quota::per_minute(DEFAULT_REQUESTS_PER_MINUTE)),
+                RateLimiter::direct(Quota::per_hour(DEFAULT_REQUESTS_PER_HOUR)),
+            ],
+            concurrency: Arc::new(Semaphore::new(DEFAULT_MAX_CONCURRENT_REQUESTS)),
+            backoff: Backoff {
+                max_attempts: DEFAULT_MAX_ATTEMPTS,
+                base_delay: DEFAULT_RETRY_BASE_DELAY,
+            },
+        }
+    }
+
+    /// Retries failed queries according to `backoff` instead of the default policy
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Logs in again, replacing the session cookie
+    ///
+    /// Unless forced, this is skipped if the last login was so recent that another
+    /// request has most likely just refreshed the session. Concurrent calls wait for the
+    /// login that is already underway instead of starting their own, and a login that failed
+    /// isn't tried again for a while no matter how many queries need one.
+    pub async fn reauth(&self, force: bool) -> Result<(), Error> {
+        let requested = Instant::now();
+        let mut logins = self.logins.lock().await;
+
+        // Someone else logged in while we were waiting, so that session is as fresh as ours would be
+        if logins
+            .succeeded
+            .is_some_and(|succeeded| succeeded >= requested)
+        {
+            return Ok(());
+        }
+
+        // Trying again right away would most likely fail the same way, or get the account locked
+        if let Some((failed, e)) = &logins.failed {
+            if failed.elapsed() < REAUTH_COOLDOWN {
+                return Err(e.clone());
+            }
+        }
+
+        if !force
+            && logins
+                .succeeded
+                .is_some_and(|succeeded| succeeded.elapsed() < REAUTH_THROTTLE)
+        {
+            return Ok(());
+        }
+
+        match self.login().await {
+            Ok(()) => {
+                logins.succeeded = Some(Instant::now());
+                logins.failed = None;
+                Ok(())
+            }
+            // We didn't even get to try
+            Err(e @ Error::RateLimited { .. }) => Err(e),
+            Err(e) => {
+                logins.failed = Some((Instant::now(), e.clone()));
+                Err(e)
+            }
+        }
+    }
+
+    async fn login(&self) -> Result<(), Error> {
+        metrics::counter!("gpcache_reauths_total").increment(1);
+        info!("Logging in to Space-Track");
+
+        let params = [("identity", &self.user), ("password", &self.pass)];
+
+        let request = self
+            .client
+            .post(format!("{}{AUTH_PATH}", self.base_url))
+            .form(&params);
+        let in_flight = self.send(request).await?;
+        let body = in_flight.response.error_for_status()?.text().await?;
+
+        // Rejected credentials still come back as a 200
+        if body.contains("\"Failed\"") {
+            return Err(Error::Unauthorized("credentials were rejected".into()));
+        }
+
+        Ok(())
+    }
+
+    /// Whether the last login was too recent for its session to have expired already
+    async fn logged_in_recently(&self) -> bool {
+        self.logins
+            .lock()
+            .await
+            .succeeded
+            .is_some_and(|succeeded| succeeded.elapsed() < REAUTH_COOLDOWN)
+    }
+
+    /// Runs a query against the given API path, logging in first if there is no valid session
+    async fn request(&self, path: String) -> Result<InFlight, Error> {
+        let query = format!("{}{path}", self.base_url);
+
+        let start = Instant::now();
+        let mut in_flight = self.get(&query).await?;
+
+        // There is no session until the first query, and it silently expires after a while.
+        // If a throttled reauth didn't help, the fresh session is bad too and we force one,
+        // unless the last login was so recent that another one won't change anything.
+        for force in [false, true] {
+            if !is_auth_failure(&in_flight.response) || (force && self.logged_in_recently().await) {
+                break;
+            }
+
+            // The login needs a permit of its own
+            drop(in_flight);
+            self.reauth(force).await?;
+            in_flight = self.get(&query).await?;
+        }
+
+        let InFlight { response, _permit } = in_flight;
+
+        let elapsed = start.elapsed();
+        if elapsed > self.slow_query_threshold {
+            warn!(?elapsed, %query, "Slow Space-Track query");
+        }
+
+        if response.status() == StatusCode::UNAUTHORIZED {
+            return Err(Error::Unauthorized(format!(
+                "session was rejected for {query}"
+            )));
+        }
+
+        // A recent login went through, so this is more likely a maintenance page than our session
+        if is_auth_failure(&response) {
+            return Err(Error::InvalidResponse(format!(
+                "got an HTML page for {query}, likely announcing maintenance"
+            )));
+        }
+
+        Ok(InFlight {
+            response: response.error_for_status()?,
+            _permit,
+        })
+    }
+
+    /// Sends a GET request, retrying connection errors and server errors with backoff
+    async fn get(&self, url: &str) -> Result<InFlight, Error> {
+        let mut attempt = 1;
+
+        loop {
+            let result = self.send(self.client.get(url)).await;
+
+            let retry_in = match &result {
+                Ok(in_flight) if in_flight.response.status().is_server_error() => {
+                    self.backoff.delay(attempt - 1)
+                }
+                Err(Error::Upstream(e)) if e.is_connect() || e.is_request() => {
+                    self.backoff.delay(attempt - 1)
+                }
+                Err(Error::Timeout(_)) => self.backoff.delay(attempt - 1),
+                // Not worth holding on to the client for any longer than that
+                Err(Error::RateLimited { retry_after }) if *retry_after <= MAX_THROTTLE_WAIT => {
+                    *retry_after
+                }
+                _ => return result,
+            };
+
+            if attempt >= self.backoff.max_attempts {
+                return result;
+            }
+
+            let reason = match result {
+                Ok(in_flight) => in_flight.response.status().to_string(),
+                Err(e) => e.to_string(),
+            };
+            warn!(?retry_in, %reason, %url, attempt, "Retrying Space-Track query");
+
+            tokio::time::sleep(retry_in).await;
+            attempt += 1;
+        }
+    }
+
+    /// Sends a request once the rate limits and the number of other requests underway allow it
+    async fn send(&self, request: RequestBuilder) -> Result<InFlight, Error> {
+        self.throttle().await?;
+
+        let permit = self
+            .concurrency
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("semaphore is never closed");
+
+        metrics::counter!("gpcache_upstream_requests_total").increment(1);
+        let response = request.send().await?;
+
+        if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = response
+                .headers()
+                .get(header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok()?.parse().ok())
+                .map_or(DEFAULT_RETRY_AFTER, Duration::from_secs);
+
+            return Err(Error::RateLimited { retry_after });
+        }
+
+        Ok(InFlight {
+            response,
+            _permit: permit,
+        })
+    }
+
+    async fn throttle(&self) -> Result<(), Error> {
+        for limiter in &self.limiters {
+            if let Err(not_until) = limiter.check() {
+                let retry_after = not_until.wait_time_from(DefaultClock::default().now());
+                if retry_after > MAX_THROTTLE_WAIT {
+                    return Err(Error::RateLimited { retry_after });
+                }
+
+                limiter.until_ready().await;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Proxies from `HTTPS_PROXY` and `HTTP_PROXY`, skipping the hosts in `NO_PROXY`
+fn proxies() -> Result<Vec<Proxy>, ConfigError> {
+    let env = |name: &str| {
+        std::env::var(name)
+            .or_else(|_| std::env::var(name.to_lowercase()))
+            .ok()
+            .filter(|url| !url.is_empty())
+    };
+
+    let invalid =
+        |name: &str, e: reqwest::Error| ConfigError(format!("Invalid proxy URL in {name}: {e}"));
+
+    let mut proxies = Vec::new();
+    if let Some(url) = env("HTTPS_PROXY") {
+        proxies.push(Proxy::https(url).map_err(|e| invalid("HTTPS_PROXY", e))?);
+    }
+    if let Some(url) = env("HTTP_PROXY") {
+        proxies.push(Proxy::http(url).map_err(|e| invalid("HTTP_PROXY", e))?);
+    }
+
+    Ok(proxies
+        .into_iter()
+        .map(|proxy| proxy.no_proxy(NoProxy::from_env()))
+        .collect())
+}
+
+/// Identifies us to Space-Track, with a way to reach the operator from `GPCACHE_CONTACT`
+fn user_agent() -> Result<header::HeaderValue, ConfigError> {
+    let version = env!("CARGO_PKG_VERSION");
+    let user_agent = match std::env::var("GPCACHE_CONTACT") {
+        Ok(contact) => format!("gpcache/{version} (+{})", contact.trim()),
+        Err(_) => format!("gpcache/{version}"),
+    };
+
+    header::HeaderValue::from_str(&user_agent)
+        .map_err(|_| ConfigError("GPCACHE_CONTACT must not contain control characters".into()))
+}
+
+/// Extra root certificates from the PEM file at `GPCACHE_CA_BUNDLE`, e.g. for an intercepting proxy
+fn ca_bundle() -> Result<Option<Certificate>, ConfigError> {
+    let Ok(path) = std::env::var("GPCACHE_CA_BUNDLE") else {
+        return Ok(None);
+    };
+    let pem = std::fs::read(&path)
+        .map_err(|e| ConfigError(format!("Failed to read CA bundle {path}: {e}")))?;
+
+    // Only checked once the client is built, where the error doesn't name the file
+    if !pem
+        .windows(b"-----BEGIN CERTIFICATE-----".len())
+        .any(|window| window == b"-----BEGIN CERTIFICATE-----")
+    {
+        return Err(ConfigError(format!(
+            "No PEM certificates found in CA bundle {path}"
+        )));
+    }
+
+    Certificate::from_pem(&pem)
+        .map(Some)
+        .map_err(|e| ConfigError(format!("Invalid CA bundle {path}: {e}")))
+}
+
+/// Expired sessions either get a 401 or a 200 with the HTML login page instead of the data
+fn is_auth_failure(response: &Response) -> bool {
+    let html = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/html"));
+
+    response.status() == StatusCode::UNAUTHORIZED || html
+}
+
+/// Response body as it comes in
+pub type Body = BoxStream<'static, Result<Bytes, Error>>;
+
+/// Runs Space-Track queries, implemented by something other than [`SpaceTrackClient`] to run the
+/// caches without the real API
+#[poem::async_trait]
+pub trait UpstreamClient: Send + Sync {
+    /// Runs a query against the given API path, e.g. `/basicspacedata/query/class/gp/...`
+    async fn query(&self, path: String) -> Result<Body, Error>;
+}
+
+#[poem::async_trait]
+impl UpstreamClient for SpaceTrackClient {
+    async fn query(&self, path: String) -> Result<Body, Error> {
+        // The permit is held until the whole body has been read
+        let in_flight = self.request(path).await?;
+
+        let body = futures_util::stream::unfold(in_flight, |mut in_flight| async move {
+            match in_flight.response.chunk().await {
+                Ok(Some(chunk)) => Some((Ok(chunk), in_flight)),
+                Ok(None) => None,
+                Err(e) => Some((Err(e.into()), in_flight)),
+            }
+        });
+
+        Ok(body.boxed())
+    }
+}
+
+impl dyn UpstreamClient {
+    /// Runs a query and reads the whole body it responds with
+    pub async fn query_bytes(&self, path: String) -> Result<Bytes, Error> {
+        let mut body = self.query(path).await?;
+
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.next().await {
+            bytes.extend_from_slice(&chunk?);
+        }
+
+        Ok(bytes.into())
+    }
+
+    /// Runs a query that responds with plain text, like the TLE formats
+    pub async fn query_text(&self, path: String) -> Result<String, Error> {
+        let body = self.query_bytes(path).await?;
+        Ok(String::from_utf8_lossy(&body).into_owned())
+    }
+
+    /// Runs a query and deserializes the JSON it responds with
+    pub async fn query_json<T: DeserializeOwned>(&self, path: String) -> Result<T, Error> {
+        let body = self.query_bytes(path.clone()).await?;
+
+        // Error pages don't always come with the right content type
+        if body.trim_ascii_start().starts_with(b"<") {
+            return Err(Error::InvalidResponse(format!(
+                "got markup instead of JSON for {path}, possibly a maintenance page"
+            )));
+        }
+
+        serde_json::from_slice(&body)
+            .map_err(|e| Error::InvalidResponse(format!("malformed JSON for {path}: {e}")))
+    }
+
+    /// Runs a query that responds with a JSON array, reading its elements as they arrive
+    ///
+    /// Keeps large responses from being buffered in their entirety before they are deserialized.
+    pub async fn query_json_elements(&self, path: String) -> Result<JsonElements, Error> {
+        let body = self.query(path.clone()).await?;
+
+        Ok(JsonElements {
+            body,
+            path,
+            chunk: Bytes::new(),
+            offset: 0,
+            state: ArrayState::Start,
+            element: Vec::new(),
+            depth: 0,
+            in_string: false,
+            escaped: false,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArrayState {
+    Start,
+    /// Right after the opening bracket, where the array may also end
+    First,
+    /// After a comma, where another element has to follow
+    Next,
+    Element,
+    /// After an element, where either a comma or the closing bracket has to follow
+    Separator,
+    Done,
+}
+
+/// Elements of a JSON array response as read by `query_json_elements`
+///
+/// Only tracks nesting and strings to tell where an element ends, the elements themselves are
+/// left for the caller to deserialize.
+pub struct JsonElements {
+    body: Body,
+    path: String,
+    chunk: Bytes,
+    offset: usize,
+    state: ArrayState,
+    element: Vec<u8>,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl JsonElements {
+    /// Raw JSON of the next element, or `None` once the array is closed
+    pub async fn next(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        loop {
+            while let Some(&byte) = self.chunk.get(self.offset) {
+                self.offset += 1;
+
+                if let Some(element) = self.scan(byte)? {
+                    return Ok(Some(element));
+                }
+            }
+
+            if self.state == ArrayState::Done {
+                return Ok(None);
+            }
+
+            match self.body.next().await.transpose()? {
+                Some(chunk) => {
+                    self.chunk = chunk;
+                    self.offset = 0;
+                }
+                None => return Err(self.invalid("ended early")),
+            }
+        }
+    }
+
+    /// Feeds a byte through, returning the element it completes
+    fn scan(&mut self, byte: u8) -> Result<Option<Vec<u8>>, Error> {
+        if self.state == ArrayState::Element {
+            return Ok(self.extend(byte));
+        }
+
+        if byte.is_ascii_whitespace() || self.state == ArrayState::Done {
+            return Ok(None);
+        }
+
+        match (self.state, byte) {
+            (ArrayState::Start, b'[') => self.state = ArrayState::First,
+            // Error pages don't always come with the right content type
+            (ArrayState::Start, b'<') => {
+                return Err(Error::InvalidResponse(format!(
+                    "got markup instead of JSON for {}, possibly a maintenance page",
+                    self.path
+                )))
+            }
+            (ArrayState::Start, _) => {
+                return Err(Error::InvalidResponse(format!(
+                    "expected a JSON array for {}",
+                    self.path
+                )))
+            }
+            (ArrayState::First | ArrayState::Separator, b']') => self.state = ArrayState::Done,
+            (ArrayState::Separator, b',') => self.state = ArrayState::Next,
+            (ArrayState::Separator, _) => return Err(self.invalid("is missing a comma")),
+            (ArrayState::First | ArrayState::Next, b',' | b']') => {
+                return Err(self.invalid("is missing an element"))
+            }
+            (ArrayState::First | ArrayState::Next, _) => {
+                self.state = ArrayState::Element;
+                return Ok(self.extend(byte));
+            }
+            (ArrayState::Element | ArrayState::Done, _) => unreachable!("handled above"),
+        }
+
+        Ok(None)
+    }
+
+    /// Adds a byte to the current element, returning the element if it is complete
+    fn extend(&mut self, byte: u8) -> Option<Vec<u8>> {
+        if self.in_string {
+            self.element.push(byte);
+
+            if self.escaped {
+                self.escaped = false;
+            } else if byte == b'\\' {
+                self.escaped = true;
+            } else if byte == b'"' {
+                self.in_string = false;
+                return self.complete_if_closed();
+            }
+
+            return None;
+        }
+
+        match byte {
+            b'"' => self.in_string = true,
+            b'{' | b'[' => self.depth += 1,
+            b'}' | b']' if self.depth > 0 => {
+                self.depth -= 1;
+                self.element.push(byte);
+                return self.complete_if_closed();
+            }
+            // Only scalars are still open at the top level, and these end them
+            b',' if self.depth == 0 => return Some(self.take(ArrayState::Next)),
+            b']' if self.depth == 0 => return Some(self.take(ArrayState::Done)),
+            _ if byte.is_ascii_whitespace() && self.depth == 0 => {
+                return Some(self.take(ArrayState::Separator))
+            }
+            _ => {}
+        }
+
+        self.element.push(byte);
+        None
+    }
+
+    fn complete_if_closed(&mut self) -> Option<Vec<u8>> {
+        (self.depth == 0).then(|| self.take(ArrayState::Separator))
+    }
+
+    fn take(&mut self, next: ArrayState) -> Vec<u8> {
+        self.state = next;
+        std::mem::take(&mut self.element)
+    }
+
+    fn invalid(&self, problem: &str) -> Error {
+        Error::InvalidResponse(format!("JSON array for {} {problem}", self.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        gp::GpRecord, mock::MockClient, perturbation::PerturbationCache,
+        satellites::SatelliteDatabase,
+    };
+    use std::{
+        num::NonZeroUsize,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+    use tokio::net::{TcpListener, TcpStream};
+    use wiremock::{
+        matchers::{body_string_contains, header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    const ISS: &str = include_str!("fixtures/iss_gp.json");
+    const SATCAT: &str = include_str!("fixtures/satcat.json");
+    const GP_PATH: &str = "/basicspacedata/query/class/gp/NORAD_CAT_ID/25544";
+    const SATCAT_PATH: &str =
+        "/basicspacedata/query/class/satcat/orderby/NORAD_CAT_ID%20asc/emptyresult/show";
+    const SESSION: &str = "chocolatechip=session";
+    const SESSION_COOKIE: &str = "chocolatechip=session; Path=/";
+
+    fn json(body: &str) -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_raw(body, "application/json")
+    }
+
+    /// Logins that hand out a session cookie, expected to happen `logins` times
+    async fn mock_login(server: &MockServer, logins: u64) {
+        Mock::given(method("POST"))
+            .and(path(AUTH_PATH))
+            .and(body_string_contains("identity=user"))
+            .respond_with(json("\"\"").insert_header("set-cookie", SESSION_COOKIE))
+            .expect(logins)
+            .mount(server)
+            .await;
+    }
+
+    /// Answers `path` with `body` for requests with a session and with a 401 for everything else
+    async fn mock_query(server: &MockServer, query_path: &str, body: &str) {
+        Mock::given(method("GET"))
+            .and(path(query_path))
+            .and(header("cookie", SESSION))
+            .respond_with(json(body))
+            .mount(server)
+            .await;
+
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(401))
+            .with_priority(10)
+            .mount(server)
+            .await;
+    }
+
+    fn client(server: &MockServer) -> Arc<dyn UpstreamClient> {
+        Arc::new(SpaceTrackClient::new(&server.uri(), "user".into(), "pass".into()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn logs_in_before_the_first_query() {
+        let server = MockServer::start().await;
+        mock_login(&server, 1).await;
+        mock_query(&server, GP_PATH, ISS).await;
+
+        let client = client(&server);
+        let records: Vec<GpRecord> = client.query_json(GP_PATH.into()).await.unwrap();
+        assert_eq!(records[0].norad_cat_id, 25544);
+
+        // The session is reused from then on
+        let records: Vec<GpRecord> = client.query_json(GP_PATH.into()).await.unwrap();
+        assert_eq!(records.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn caches_query_space_track_for_what_they_are_missing() {
+        let server = MockServer::start().await;
+        mock_login(&server, 1).await;
+        mock_query(&server, SATCAT_PATH, SATCAT).await;
+        mock_query(&server, GP_PATH, ISS).await;
+        let batch_path = "/basicspacedata/query/class/gp/NORAD_CAT_ID/20580,44713";
+        mock_query(&server, batch_path, "[]").await;
+
+        let client = client(&server);
+        let max_age = Duration::from_secs(60);
+        let db = SatelliteDatabase::new(client.clone(), max_age);
+        db.update().await.unwrap();
+        assert_eq!(db.len(), 10);
+
+        let capacity = NonZeroUsize::new(100).unwrap();
+        let cache = PerturbationCache::new(client, max_age, max_age, capacity);
+        assert!(!cache.get_or_fetch(25544, None).await.unwrap().hit);
+        assert!(cache.get_or_fetch(25544, None).await.unwrap().hit);
+        assert!(cache
+            .get_or_fetch_many(&[25544, 44713, 20580])
+            .await
+            .contains_key(&25544));
+
+        // The first query ran into the missing session, everything after that is a cache miss
+        let queries = server
+            .received_requests()
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|request| request.method.as_str() == "GET")
+            .map(|request| request.url.path().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(queries, [SATCAT_PATH, SATCAT_PATH, GP_PATH, batch_path]);
+    }
+
+    #[tokio::test]
+    async fn connections_are_reused_across_queries() {
+        let server = MockServer::start().await;
+        mock_login(&server, 1).await;
+        mock_query(&server, GP_PATH, ISS).await;
+
+        // Sits in between to count the connections made to Space-Track
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_url = format!("http://{}", proxy.local_addr().unwrap());
+        let upstream = *server.address();
+        let connections = Arc::new(AtomicUsize::new(0));
+        tokio::spawn({
+            let connections = connections.clone();
+            async move {
+                while let Ok((mut inbound, _)) = proxy.accept().await {
+                    connections.fetch_add(1, Ordering::Relaxed);
+                    tokio::spawn(async move {
+                        let mut outbound = TcpStream::connect(upstream).await.unwrap();
+                        let _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+                    });
+                }
+            }
+        });
+
+        let client: Arc<dyn UpstreamClient> =
+            Arc::new(SpaceTrackClient::new(&proxy_url, "user".into(), "pass".into()).unwrap());
+        for _ in 0..3 {
+            client.query_bytes(GP_PATH.into()).await.unwrap();
+        }
+
+        // The login and the queries before and after it all went over the same connection
+        assert_eq!(connections.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn reads_satcat_elements() {
+        let server = MockServer::start().await;
+        mock_login(&server, 1).await;
+        mock_query(&server, SATCAT_PATH, SATCAT).await;
+
+        let mut elements = client(&server)
+            .query_json_elements(SATCAT_PATH.into())
+            .await
+            .unwrap();
+
+        let mut count = 0;
+        while let Some(element) = elements.next().await.unwrap() {
+            serde_json::from_slice::<serde_json::Value>(&element).unwrap();
+            count += 1;
+        }
+        assert_eq!(count, 10);
+    }
+
+    #[tokio::test]
+    async fn expired_session_is_renewed_and_the_query_retried() {
+        let server = MockServer::start().await;
+        mock_login(&server, 1).await;
+        mock_query(&server, GP_PATH, ISS).await;
+
+        // Logged in a while ago, but too recently for a throttled reauth to do anything
+        let client = SpaceTrackClient::new(&server.uri(), "user".into(), "pass".into()).unwrap();
+        client.logins.lock().await.succeeded = Some(Instant::now() - REAUTH_COOLDOWN * 2);
+
+        // The session expires, and the throttled reauth doesn't help so a forced one follows
+        Mock::given(method("GET"))
+            .and(path(GP_PATH))
+            .respond_with(ResponseTemplate::new(401))
+            .up_to_n_times(2)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+
+        let client: Arc<dyn UpstreamClient> = Arc::new(client);
+        let records: Vec<GpRecord> = client.query_json(GP_PATH.into()).await.unwrap();
+        assert_eq!(records[0].norad_cat_id, 25544);
+    }
+
+    #[tokio::test]
+    async fn login_page_triggers_a_login_instead_of_being_parsed() {
+        let server = MockServer::start().await;
+        mock_login(&server, 1).await;
+        Mock::given(method("GET"))
+            .and(path(GP_PATH))
+            .and(header("cookie", SESSION))
+            .respond_with(json(ISS))
+            .mount(&server)
+            .await;
+        // Expired sessions get the login page with a 200
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                "<html><body>Login</body></html>",
+                "text/html; charset=UTF-8",
+            ))
+            .with_priority(10)
+            .mount(&server)
+            .await;
+
+        let records: Vec<GpRecord> = client(&server).query_json(GP_PATH.into()).await.unwrap();
+        assert_eq!(records[0].norad_cat_id, 25544);
+    }
+
+    #[tokio::test]
+    async fn login_page_after_a_fresh_login_is_an_error() {
+        let server = MockServer::start().await;
+        // The fresh session rules out a bad one, for this query and the ones after it
+        mock_login(&server, 1).await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_raw("<html>Maintenance</html>", "text/html"),
+            )
+            .mount(&server)
+            .await;
+
+        let client = client(&server);
+        for _ in 0..3 {
+            let result = client.query_bytes(GP_PATH.into()).await;
+            assert!(matches!(result, Err(Error::InvalidResponse(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried_with_the_given_backoff() {
+        let server = MockServer::start().await;
+        mock_login(&server, 1).await;
+        mock_query(&server, GP_PATH, ISS).await;
+        Mock::given(method("GET"))
+            .and(path(GP_PATH))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+
+        let backoff = |max_attempts| Backoff {
+            max_attempts,
+            base_delay: Duration::from_millis(1),
+        };
+        let client = |max_attempts| -> Arc<dyn UpstreamClient> {
+            Arc::new(
+                SpaceTrackClient::new(&server.uri(), "user".into(), "pass".into())
+                    .unwrap()
+                    .with_backoff(backoff(max_attempts)),
+            )
+        };
+
+        // A single attempt gives up on the first error, more get past the second one
+        let result = client(1).query_bytes(GP_PATH.into()).await;
+        assert!(matches!(result, Err(Error::Upstream(_))));
+        let records: Vec<GpRecord> = client(3).query_json(GP_PATH.into()).await.unwrap();
+        assert_eq!(records[0].norad_cat_id, 25544);
+    }
+
+    #[tokio::test]
+    async fn rejected_credentials_are_reported() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path(AUTH_PATH))
+            .respond_with(json(r#"{"Login":"Failed"}"#))
+            .mount(&server)
+            .await;
+        mock_query(&server, GP_PATH, ISS).await;
+
+        let result = client(&server).query_bytes(GP_PATH.into()).await;
+        assert!(matches!(result, Err(Error::Unauthorized(_))));
+    }
+
+    #[tokio::test]
+    async fn failed_logins_are_not_retried_right_away() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path(AUTH_PATH))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&server)
+            .await;
+        mock_query(&server, GP_PATH, ISS).await;
+
+        // Every query needs a session, but only the first one gets to try logging in
+        let client = client(&server);
+        let queries = (0..5).map(|_| client.query_bytes(GP_PATH.into()));
+        for result in futures_util::future::join_all(queries).await {
+            assert!(matches!(result, Err(Error::Upstream(_))));
+        }
+    }
+
+    /// Elements of `body` as split up when it arrives `chunk_size` bytes at a time
+    async fn elements(body: &str, chunk_size: usize) -> Result<Vec<String>, Error> {
+        let client: Arc<dyn UpstreamClient> = Arc::new(
+            MockClient::new()
+                .respond("/array", body.to_owned())
+                .with_chunk_size(chunk_size),
+        );
+        let mut elements = client.query_json_elements("/array".into()).await?;
+
+        let mut split = Vec::new();
+        while let Some(element) = elements.next().await? {
+            split.push(String::from_utf8(element).unwrap());
+        }
+        Ok(split)
+    }
+
+    /// Splits `body` with any chunk size and checks it comes out the same every time
+    async fn split(body: &str) -> Result<Vec<String>, Error> {
+        let whole = elements(body, body.len()).await;
+
+        for chunk_size in 1..body.len() {
+            let chunked = elements(body, chunk_size).await;
+            assert_eq!(
+                format!("{chunked:?}"),
+                format!("{whole:?}"),
+                "chunk size {chunk_size}"
+            );
+        }
+
+        whole
+    }
+
+    #[tokio::test]
+    async fn splits_objects_with_brackets_and_quotes_in_strings() {
+        let body = r#"[{"a":"]},[{"}, {"b":"say \"hi\", }"},{"c":[1,{"d":"\\"}]}]"#;
+
+        assert_eq!(
+            split(body).await.unwrap(),
+            [
+                r#"{"a":"]},[{"}"#,
+                r#"{"b":"say \"hi\", }"}"#,
+                r#"{"c":[1,{"d":"\\"}]}"#,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn splits_scalars() {
+        let body = r#" [1, "two,]" ,true,null , -3.5e2 ] "#;
+
+        assert_eq!(
+            split(body).await.unwrap(),
+            ["1", r#""two,]""#, "true", "null", "-3.5e2"]
+        );
+    }
+
+    #[tokio::test]
+    async fn splits_empty_arrays() {
+        assert!(split("[]").await.unwrap().is_empty());
+        assert!(split(" [ \n ] ").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn splits_the_satcat() {
+        let whole = elements(SATCAT, SATCAT.len()).await.unwrap();
+        assert_eq!(whole.len(), 10);
+        assert_eq!(elements(SATCAT, 1).await.unwrap(), whole);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_arrays() {
+        for body in [
+            "[1 2]",
+            r#"[{"a":1} {"b":2}]"#,
+            r#"[{"a":1},]"#,
+            "[1,]",
+            "[,1]",
+            "[1,,2]",
+            r#"{"a":1}"#,
+            r#"[{"a":1},{"b""#,
+            "[1,2",
+            "",
+        ] {
+            let result = split(body).await;
+            assert!(
+                matches!(result, Err(Error::InvalidResponse(_))),
+                "{body}: {result:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_html() {
+        let result = split("\n<html><body>Maintenance</body></html>").await;
+        assert!(matches!(result, Err(Error::InvalidResponse(e)) if e.contains("markup")));
     }
 }
@@ -0,0 +1,44 @@
+use crate::error::Error;
+use poem::{http::header, FromRequest, IntoResponse, Request, RequestBody, Result};
+use std::sync::Arc;
+
+/// Token admin requests have to present as `Authorization: Bearer <token>`, if there is one
+#[derive(Clone)]
+pub struct AdminToken(pub Option<Arc<str>>);
+
+/// Extracted only from requests carrying the admin token, rejecting all others with a 401
+pub struct Admin;
+
+#[poem::async_trait]
+impl<'a> FromRequest<'a> for Admin {
+    async fn from_request(req: &'a Request, _body: &mut RequestBody) -> Result<Self> {
+        let presented = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        // Without a configured token nobody is an admin
+        let token = req
+            .data::<AdminToken>()
+            .and_then(|token| token.0.as_deref());
+        let accepted = match (token, presented) {
+            (Some(token), Some(presented)) => {
+                constant_time_eq(token.as_bytes(), presented.trim().as_bytes())
+            }
+            _ => false,
+        };
+
+        if accepted {
+            Ok(Self)
+        } else {
+            let e = Error::Unauthenticated("this needs a valid admin token".into());
+            Err(poem::Error::from_response(e.into_response()))
+        }
+    }
+}
+
+/// Compares without returning early so the time taken doesn't give away how much of a guess was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
@@ -0,0 +1,729 @@
+use crate::{error::Error, locks::Recover};
+use lru::LruCache;
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fmt::Display,
+    future::Future,
+    hash::Hash,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio::sync::watch;
+use tracing::warn;
+
+// Entries checked for being too old to serve when making room, the least recently used ones are
+// the likeliest to be and checking all of them would hold the lock for too long
+const MAX_PURGE_SCAN: usize = 16;
+
+/// Data served by [`Cache::get_or_fetch`] along with where it came from
+pub struct CachedData<T> {
+    pub data: Arc<T>,
+    /// Time elapsed since the data was fetched from upstream
+    pub age: Duration,
+    /// Age at which the data is due to be refreshed
+    pub max_age: Duration,
+    /// Whether the data was served from the cache instead of being fetched
+    pub hit: bool,
+    /// Whether the data is past its maximum age, either because it is being refreshed in the
+    /// background or because refreshing it failed
+    pub stale: bool,
+}
+
+/// How a lookup was answered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disposition {
+    /// From the cache, and still fresh
+    Hit,
+    /// From the cache, but past its maximum age
+    Stale,
+    /// Fetched from upstream
+    Miss,
+}
+
+impl Disposition {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Disposition::Hit => "HIT",
+            Disposition::Stale => "STALE",
+            Disposition::Miss => "MISS",
+        }
+    }
+}
+
+impl<T> CachedData<T> {
+    /// Time left until the data is due to be refreshed
+    pub fn ttl(&self) -> Duration {
+        self.max_age.saturating_sub(self.age)
+    }
+
+    pub fn disposition(&self) -> Disposition {
+        if self.stale {
+            Disposition::Stale
+        } else if self.hit {
+            Disposition::Hit
+        } else {
+            Disposition::Miss
+        }
+    }
+}
+
+impl<T> Clone for CachedData<T> {
+    fn clone(&self) -> Self {
+        Self {
+            data: self.data.clone(),
+            age: self.age,
+            max_age: self.max_age,
+            hit: self.hit,
+            stale: self.stale,
+        }
+    }
+}
+
+/// Bounds on how old the cached data served for a single lookup may be
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Freshness {
+    /// Age at which the data is due to be refreshed
+    pub max_age: Duration,
+    /// Age up to which expired data is served while being refreshed in the background
+    pub max_stale: Duration,
+}
+
+impl Freshness {
+    /// Only serves data younger than `max_age`
+    ///
+    /// Data that is past our own maximum age but within the requested one is served while being
+    /// refreshed, the same as stale data.
+    pub fn within(self, max_age: Duration) -> Self {
+        Self {
+            max_age: self.max_age.min(max_age),
+            max_stale: max_age,
+        }
+    }
+
+    pub fn scaled(self, factor: f64) -> Self {
+        Self {
+            max_age: self.max_age.mul_f64(factor),
+            max_stale: self.max_stale.mul_f64(factor),
+        }
+    }
+}
+
+/// Outcome of an upstream fetch that concurrent misses for the same key wait on
+type Flight<V> = watch::Sender<Option<Result<CachedData<V>, Error>>>;
+
+/// Adjusts the cache-wide bounds for a single key
+type KeyFreshness<K> = Arc<dyn Fn(K, Freshness) -> Freshness + Send + Sync>;
+
+struct CacheEntry<V> {
+    /// Wall clock time so entries keep their age when persisted across restarts
+    fetched: SystemTime,
+    data: Arc<V>,
+    /// Number of requests for this key, carried over when the entry is refreshed
+    accesses: u64,
+}
+
+impl<V> Clone for CacheEntry<V> {
+    fn clone(&self) -> Self {
+        Self {
+            fetched: self.fetched,
+            data: self.data.clone(),
+            accesses: self.accesses,
+        }
+    }
+}
+
+impl<V> CacheEntry<V> {
+    fn age(&self) -> Duration {
+        // A clock that jumped backwards makes the entry look brand new rather than failing
+        self.fetched.elapsed().unwrap_or_default()
+    }
+}
+
+/// An entry taken out of the cache, e.g. to persist it
+pub struct Snapshot<K, V> {
+    pub key: K,
+    pub fetched: SystemTime,
+    pub accesses: u64,
+    pub data: Arc<V>,
+}
+
+/// Time-bounded cache in front of an upstream fetch
+pub struct Cache<K, V> {
+    /// Ordered by last access so the least recently used entry is evicted when full
+    entries: Arc<Mutex<LruCache<K, CacheEntry<V>>>>,
+    in_flight: Arc<Mutex<HashMap<K, Flight<V>>>>,
+    max_age: Duration,
+    /// Age up to which expired entries are served while being refreshed in the background
+    max_stale: Duration,
+    key_freshness: Option<KeyFreshness<K>>,
+}
+
+impl<K, V> Clone for Cache<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            entries: self.entries.clone(),
+            in_flight: self.in_flight.clone(),
+            max_age: self.max_age,
+            max_stale: self.max_stale,
+            key_freshness: self.key_freshness.clone(),
+        }
+    }
+}
+
+/// Marks a key as being fetched, clearing the mark even if the fetch is cancelled
+struct InFlight<K: Hash + Eq, V> {
+    in_flight: Arc<Mutex<HashMap<K, Flight<V>>>>,
+    key: K,
+    done: bool,
+}
+
+impl<K: Hash + Eq, V> InFlight<K, V> {
+    fn finish(mut self, result: Result<CachedData<V>, Error>) {
+        self.done = true;
+
+        let flight = self.in_flight.lock_or_recover().remove(&self.key);
+
+        if let Some(flight) = flight {
+            flight.send_replace(Some(result));
+        }
+    }
+}
+
+impl<K: Hash + Eq, V> Drop for InFlight<K, V> {
+    fn drop(&mut self) {
+        // Waiters notice the closed channel and fetch on their own
+        if !self.done {
+            self.in_flight.lock_or_recover().remove(&self.key);
+        }
+    }
+}
+
+impl<K, V> Cache<K, V>
+where
+    K: Hash + Eq + Copy + Display + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(LruCache::unbounded())),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            max_age,
+            max_stale: max_age,
+            key_freshness: None,
+        }
+    }
+
+    /// Bounds the number of entries, evicting the least recently used ones beyond it
+    pub fn with_capacity(self, capacity: NonZeroUsize) -> Self {
+        self.entries.lock_or_recover().resize(capacity);
+        self
+    }
+
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Serves entries up to `max_stale` old immediately, refreshing them in the background
+    pub fn with_max_stale(mut self, max_stale: Duration) -> Self {
+        self.max_stale = max_stale;
+        self
+    }
+
+    /// Derives the bounds for each key from the cache-wide ones, e.g. to keep some kinds of data
+    /// around for longer
+    ///
+    /// Applies everywhere entries are checked for their age, including when making room.
+    pub fn with_freshness(
+        mut self,
+        freshness: impl Fn(K, Freshness) -> Freshness + Send + Sync + 'static,
+    ) -> Self {
+        self.key_freshness = Some(Arc::new(freshness));
+        self
+    }
+
+    pub async fn get_or_fetch<F, Fut>(&self, key: K, fetch: F) -> Result<CachedData<V>, Error>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<V, Error>> + Send + 'static,
+    {
+        self.get_or_fetch_within(key, self.freshness(key), fetch)
+            .await
+    }
+
+    /// Bounds used for lookups of the key that don't bring their own
+    pub fn freshness(&self, key: K) -> Freshness {
+        let freshness = Freshness {
+            max_age: self.max_age,
+            max_stale: self.max_stale,
+        };
+
+        match &self.key_freshness {
+            Some(key_freshness) => key_freshness(key, freshness),
+            None => freshness,
+        }
+    }
+
+    /// Like [`Cache::get_or_fetch`], but with bounds on the age of the data other than our own
+    pub async fn get_or_fetch_within<F, Fut>(
+        &self,
+        key: K,
+        freshness: Freshness,
+        fetch: F,
+    ) -> Result<CachedData<V>, Error>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<V, Error>> + Send + 'static,
+    {
+        let cache_entry = self.entries.lock_or_recover().get_mut(&key).map(|entry| {
+            entry.accesses += 1;
+            entry.clone()
+        });
+
+        if let Some(entry) = &cache_entry {
+            if entry.age() < freshness.max_age {
+                return Ok(CachedData {
+                    data: entry.data.clone(),
+                    age: entry.age(),
+                    max_age: freshness.max_age,
+                    hit: true,
+                    stale: false,
+                });
+            }
+
+            if entry.age() < freshness.max_stale {
+                self.revalidate(key, entry.clone(), freshness.max_age, fetch);
+
+                return Ok(CachedData {
+                    data: entry.data.clone(),
+                    age: entry.age(),
+                    max_age: freshness.max_age,
+                    hit: true,
+                    stale: true,
+                });
+            }
+        }
+
+        // Coalesce concurrent misses so only one of them goes upstream
+        let flight = {
+            let mut in_flight = self.in_flight.lock_or_recover();
+
+            match in_flight.get(&key) {
+                Some(flight) => Err(flight.subscribe()),
+                None => {
+                    in_flight.insert(key, watch::channel(None).0);
+                    Ok(InFlight {
+                        in_flight: self.in_flight.clone(),
+                        key,
+                        done: false,
+                    })
+                }
+            }
+        };
+
+        match flight {
+            Ok(flight) => {
+                let result = self
+                    .fetch_and_insert(key, cache_entry, freshness.max_age, fetch)
+                    .await;
+                flight.finish(result.clone());
+                result
+            }
+            Err(mut waiting) => {
+                let result = waiting
+                    .wait_for(Option::is_some)
+                    .await
+                    .ok()
+                    .and_then(|result| result.clone());
+
+                match result {
+                    Some(result) => {
+                        // Accesses to existing entries have already been counted above
+                        if cache_entry.is_none() {
+                            self.count_access(key);
+                        }
+
+                        result
+                    }
+                    // The fetch we were waiting on got cancelled
+                    None => {
+                        self.fetch_and_insert(key, cache_entry, freshness.max_age, fetch)
+                            .await
+                    }
+                }
+            }
+        }
+    }
+
+    /// Number of entries currently held, expired ones included
+    pub fn len(&self) -> usize {
+        self.entries.lock_or_recover().len()
+    }
+
+    /// Cached data for the key no matter its age, counting it as an access
+    ///
+    /// Lets callers batch the fetches for many keys, entries past the maximum age are marked stale.
+    pub fn get(&self, key: K) -> Option<CachedData<V>> {
+        let max_age = self.freshness(key).max_age;
+        let mut entries = self.entries.lock_or_recover();
+        let entry = entries.get_mut(&key)?;
+        entry.accesses += 1;
+
+        Some(CachedData {
+            data: entry.data.clone(),
+            age: entry.age(),
+            max_age,
+            hit: true,
+            stale: entry.age() >= max_age,
+        })
+    }
+
+    /// Stores data fetched for a key that missed [`Cache::get`]
+    pub fn put(&self, key: K, data: V) -> Arc<V> {
+        let data = Arc::new(data);
+        let new_accesses = {
+            let entries = self.entries.lock_or_recover();
+            // Accesses to existing entries have already been counted by `get`
+            if entries.contains(&key) {
+                0
+            } else {
+                1
+            }
+        };
+
+        self.insert(key, data.clone(), new_accesses);
+        data
+    }
+
+    /// Refreshes an expired entry in a background task unless a fetch is already underway
+    fn revalidate<F, Fut>(&self, key: K, expired: CacheEntry<V>, max_age: Duration, fetch: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<V, Error>> + Send + 'static,
+    {
+        let mut in_flight = self.in_flight.lock_or_recover();
+        if in_flight.contains_key(&key) {
+            return;
+        }
+
+        in_flight.insert(key, watch::channel(None).0);
+        let flight = InFlight {
+            in_flight: self.in_flight.clone(),
+            key,
+            done: false,
+        };
+
+        let cache = self.clone();
+        tokio::spawn(async move {
+            let result = cache
+                .fetch_and_insert(key, Some(expired), max_age, fetch)
+                .await;
+            flight.finish(result);
+        });
+    }
+
+    async fn fetch_and_insert<F, Fut>(
+        &self,
+        key: K,
+        expired: Option<CacheEntry<V>>,
+        max_age: Duration,
+        fetch: F,
+    ) -> Result<CachedData<V>, Error>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, Error>>,
+    {
+        // Accesses to existing entries have already been counted in `get_or_fetch`
+        let new_accesses = if expired.is_some() { 0 } else { 1 };
+
+        let data = match fetch().await {
+            Ok(data) => Arc::new(data),
+            Err(e) => {
+                // Upstream no longer has the key, so the old data would be served forever
+                if matches!(e, Error::NotFound(_)) {
+                    self.entries.lock_or_recover().pop(&key);
+                }
+
+                // Slightly outdated data beats no data at all while upstream is having trouble
+                let Some(entry) = expired.filter(|_| e.is_transient()) else {
+                    return Err(e);
+                };
+
+                warn!(%key, error = %e, "Failed to refresh, serving stale entry");
+
+                return Ok(CachedData {
+                    age: entry.age(),
+                    max_age,
+                    data: entry.data,
+                    hit: true,
+                    stale: true,
+                });
+            }
+        };
+
+        self.insert(key, data.clone(), new_accesses);
+
+        Ok(CachedData {
+            data,
+            age: Duration::ZERO,
+            max_age,
+            hit: false,
+            stale: false,
+        })
+    }
+
+    fn count_access(&self, key: K) {
+        if let Some(entry) = self.entries.lock_or_recover().get_mut(&key) {
+            entry.accesses += 1;
+        }
+    }
+
+    /// The `top_k` most requested keys that will expire within `margin` of their maximum age
+    pub fn expiring_hot_keys(&self, top_k: usize, margin: Duration) -> Vec<K> {
+        let entries = self.entries.lock_or_recover();
+
+        let mut hottest = entries.iter().collect::<Vec<_>>();
+        hottest.sort_unstable_by_key(|(_, entry)| Reverse(entry.accesses));
+        hottest.truncate(top_k);
+
+        hottest
+            .into_iter()
+            .filter(|(key, entry)| entry.age() + margin >= self.freshness(**key).max_age)
+            .map(|(key, _)| *key)
+            .collect()
+    }
+
+    /// Unconditionally fetches fresh data for the key without counting it as an access
+    pub async fn refresh<F, Fut>(&self, key: K, fetch: F) -> Result<(), Error>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, Error>>,
+    {
+        let data = Arc::new(fetch().await?);
+        self.insert(key, data, 0);
+        Ok(())
+    }
+
+    /// Every entry along with when it was fetched and how often it was requested
+    ///
+    /// Least recently used first, so restoring them in order evicts them in the same order again.
+    pub fn snapshot(&self) -> Vec<Snapshot<K, V>> {
+        let entries = self.entries.lock_or_recover();
+
+        entries
+            .iter()
+            .rev()
+            .map(|(key, entry)| Snapshot {
+                key: *key,
+                fetched: entry.fetched,
+                accesses: entry.accesses,
+                data: entry.data.clone(),
+            })
+            .collect()
+    }
+
+    /// Snapshot of up to `limit` entries with keys after `after`, in ascending order, along with
+    /// the key to continue after if there are more
+    ///
+    /// Only the matching entries are collected while holding the lock, sorting happens after.
+    pub fn snapshot_after(&self, after: Option<K>, limit: usize) -> (Vec<Snapshot<K, V>>, Option<K>)
+    where
+        K: Ord,
+    {
+        let mut snapshots = {
+            let entries = self.entries.lock_or_recover();
+
+            entries
+                .iter()
+                .filter(|(key, _)| after.is_none_or(|after| **key > after))
+                .map(|(key, entry)| Snapshot {
+                    key: *key,
+                    fetched: entry.fetched,
+                    accesses: entry.accesses,
+                    data: entry.data.clone(),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        snapshots.sort_unstable_by_key(|snapshot| snapshot.key);
+
+        let next = (limit > 0 && snapshots.len() > limit).then(|| snapshots[limit - 1].key);
+        snapshots.truncate(limit);
+
+        (snapshots, next)
+    }
+
+    /// Puts a previously taken snapshot back unless the key has been cached in the meantime
+    pub fn restore(&self, snapshot: Snapshot<K, V>) {
+        let mut entries = self.entries.lock_or_recover();
+
+        if !entries.contains(&snapshot.key) {
+            entries.put(
+                snapshot.key,
+                CacheEntry {
+                    fetched: snapshot.fetched,
+                    data: snapshot.data,
+                    accesses: snapshot.accesses,
+                },
+            );
+        }
+    }
+
+    fn insert(&self, key: K, data: Arc<V>, new_accesses: u64) {
+        let mut entries = self.entries.lock_or_recover();
+        let accesses = entries.peek(&key).map_or(0, |entry| entry.accesses);
+
+        // Make room by dropping entries too old to ever be served before evicting live ones
+        if !entries.contains(&key) && entries.len() >= entries.cap().get() {
+            let dead = entries
+                .iter()
+                .rev()
+                .take(MAX_PURGE_SCAN)
+                .filter(|(key, entry)| entry.age() >= self.freshness(**key).max_stale)
+                .map(|(key, _)| *key)
+                .collect::<Vec<_>>();
+
+            for key in dead {
+                entries.pop(&key);
+            }
+        }
+
+        entries.put(
+            key,
+            CacheEntry {
+                fetched: SystemTime::now(),
+                data,
+                accesses: accesses + new_accesses,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn per_key_freshness_applies_to_lookups_and_making_room() {
+        let max_age = Duration::from_millis(50);
+        let cache = Cache::<u32, ()>::new(max_age)
+            .with_capacity(NonZeroUsize::new(2).unwrap())
+            .with_freshness(|key, freshness| match key {
+                1 => freshness.scaled(100.0),
+                _ => freshness,
+            });
+
+        cache.put(1, ());
+        cache.put(2, ());
+        tokio::time::sleep(max_age).await;
+
+        assert!(!cache.get(1).unwrap().stale);
+        assert!(cache.get(2).unwrap().stale);
+
+        // Only the entry that is past its own maximum staleness gets dropped
+        cache.put(3, ());
+        assert!(cache.get(1).is_some());
+        assert!(cache.get(2).is_none());
+    }
+
+    #[tokio::test]
+    async fn expired_entries_gone_upstream_are_evicted() {
+        let max_age = Duration::from_millis(50);
+        let gone = || async { Err(Error::NotFound("gone".into())) };
+
+        let cache = Cache::<u32, ()>::new(max_age);
+        cache.put(1, ());
+        tokio::time::sleep(max_age).await;
+
+        let result = cache.get_or_fetch(1, gone).await;
+        assert!(matches!(result, Err(Error::NotFound(_))));
+        assert!(cache.get(1).is_none());
+
+        // Refreshing in the background drops it all the same
+        let cache = cache.with_max_stale(Duration::from_secs(60));
+        cache.put(1, ());
+        tokio::time::sleep(max_age).await;
+
+        assert!(cache.get_or_fetch(1, gone).await.unwrap().stale);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(cache.get(1).is_none());
+    }
+
+    #[tokio::test]
+    async fn expired_entries_are_only_served_on_transient_errors() {
+        let max_age = Duration::from_millis(50);
+        let cache = Cache::<u32, ()>::new(max_age);
+        cache.put(1, ());
+        tokio::time::sleep(max_age).await;
+
+        let cached = cache
+            .get_or_fetch(1, || async {
+                Err(Error::RateLimited {
+                    retry_after: Duration::from_secs(1),
+                })
+            })
+            .await
+            .unwrap();
+        assert!(cached.stale);
+
+        let result = cache
+            .get_or_fetch(1, || async {
+                Err(Error::InvalidResponse("maintenance".into()))
+            })
+            .await;
+        assert!(matches!(result, Err(Error::InvalidResponse(_))));
+        assert!(cache.get(1).is_some());
+    }
+
+    #[tokio::test]
+    async fn restored_snapshots_keep_the_eviction_order() {
+        let capacity = NonZeroUsize::new(3).unwrap();
+        let cache = Cache::<u32, ()>::new(Duration::from_secs(60)).with_capacity(capacity);
+        cache.put(1, ());
+        cache.put(2, ());
+        cache.put(3, ());
+        cache.get(1);
+
+        let restored = Cache::<u32, ()>::new(Duration::from_secs(60)).with_capacity(capacity);
+        for snapshot in cache.snapshot() {
+            restored.restore(snapshot);
+        }
+
+        // 2 was the least recently used before, and 3 after it
+        restored.put(4, ());
+        assert!(restored.get(2).is_none());
+        restored.put(5, ());
+        assert!(restored.get(3).is_none());
+        assert!(restored.get(1).is_some());
+    }
+
+    #[tokio::test]
+    async fn making_room_only_checks_the_least_recently_used_entries() {
+        let checks = Arc::new(AtomicUsize::new(0));
+        let cache = Cache::<usize, ()>::new(Duration::from_millis(50))
+            .with_capacity(NonZeroUsize::new(100).unwrap())
+            .with_freshness({
+                let checks = checks.clone();
+                move |_, freshness| {
+                    checks.fetch_add(1, Ordering::Relaxed);
+                    freshness
+                }
+            });
+
+        for key in 0..100 {
+            cache.put(key, ());
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        cache.get(0);
+
+        // Only the dead entries at the tail are dropped, the recently used one is never checked
+        checks.store(0, Ordering::Relaxed);
+        cache.put(100, ());
+        assert_eq!(checks.load(Ordering::Relaxed), MAX_PURGE_SCAN);
+        assert_eq!(cache.len(), 100 - MAX_PURGE_SCAN + 1);
+        assert!(cache.get(0).is_some());
+    }
+}
@@ -0,0 +1,51 @@
+use poem::{FromRequest, Request, RequestBody, Result};
+use std::{fmt, net::IpAddr};
+
+/// Whether requests arrive through a reverse proxy whose forwarding headers can be believed
+#[derive(Clone, Copy, Debug)]
+pub struct TrustProxy(pub bool);
+
+/// Address of the client a request originates from, e.g. to key per-client limits on
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+#[poem::async_trait]
+impl<'a> FromRequest<'a> for ClientIp {
+    async fn from_request(req: &'a Request, _body: &mut RequestBody) -> Result<Self> {
+        let peer = req.remote_addr().as_socket_addr().map(|addr| addr.ip());
+
+        // Anybody can send these headers, so they are only honored behind a proxy that sets them
+        let trusted = req.data::<TrustProxy>().is_some_and(|trust| trust.0);
+        if !trusted {
+            return Ok(Self(peer));
+        }
+
+        // Proxies append to X-Forwarded-For, so only the last entry was written by ours
+        let forwarded = req
+            .headers()
+            .get_all("X-Forwarded-For")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .last()
+            .and_then(|ip| ip.trim().parse().ok());
+
+        let real_ip = || {
+            req.headers()
+                .get("X-Real-IP")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|ip| ip.trim().parse().ok())
+        };
+
+        Ok(Self(forwarded.or_else(real_ip).or(peer)))
+    }
+}
+
+impl fmt::Display for ClientIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(ip) => write!(f, "{ip}"),
+            None => write!(f, "unknown"),
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use std::{fmt::Display, str::FromStr, time::Duration};
+
+/// Configuration the service can't start with, meant to be shown to the operator as is
+#[derive(thiserror::Error, Debug)]
+#[error("{0}")]
+pub struct ConfigError(pub String);
+
+/// Reads an optional environment variable, failing if it is set to something unparseable
+pub fn env_var<T>(name: &str) -> Result<Option<T>, ConfigError>
+where
+    T: FromStr,
+    <T as FromStr>::Err: Display,
+{
+    let Ok(value) = std::env::var(name) else {
+        return Ok(None);
+    };
+
+    value
+        .parse()
+        .map(Some)
+        .map_err(|e| ConfigError(format!("Invalid value for env var {name}: {e}")))
+}
+
+pub fn env_millis(name: &str) -> Result<Option<Duration>, ConfigError> {
+    Ok(env_var(name)?.map(Duration::from_millis))
+}
+
+pub fn env_secs(name: &str) -> Result<Option<Duration>, ConfigError> {
+    Ok(env_var(name)?.map(Duration::from_secs))
+}
+
+/// Value of a `--name value` or `--name=value` command line flag
+pub fn cli_flag(name: &str) -> Result<Option<String>, ConfigError> {
+    let flag = format!("--{name}");
+    let mut args = std::env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args
+                .next()
+                .map(Some)
+                .ok_or_else(|| ConfigError(format!("Missing value for {flag}")));
+        }
+
+        if let Some(value) = arg
+            .strip_prefix(&flag)
+            .and_then(|rest| rest.strip_prefix('='))
+        {
+            return Ok(Some(value.to_owned()));
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_var_reports_unparseable_values() {
+        std::env::set_var("GPCACHE_TEST_ENV_VAR", "12");
+        assert_eq!(env_var::<u32>("GPCACHE_TEST_ENV_VAR").unwrap(), Some(12));
+        assert_eq!(env_var::<u32>("GPCACHE_TEST_ENV_VAR_UNSET").unwrap(), None);
+
+        std::env::set_var("GPCACHE_TEST_ENV_VAR", "twelve");
+        let e = env_secs("GPCACHE_TEST_ENV_VAR").unwrap_err();
+        assert!(e.0.contains("GPCACHE_TEST_ENV_VAR"));
+    }
+}
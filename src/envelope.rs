@@ -0,0 +1,93 @@
+use crate::error::Error;
+use chrono::{DateTime, Utc};
+use poem::{web::Json, IntoResponse, Request, Response};
+use serde::{Deserialize, Serialize};
+
+/// Media type parameter clients can send in `Accept` instead of the query flag
+const PROFILE: &str = "profile=\"envelope\"";
+
+/// Uniform response shape for generic clients, opted into per request
+#[derive(Serialize, Debug)]
+pub struct Envelope<T> {
+    data: Option<T>,
+    meta: Option<Meta>,
+    error: Option<ErrorInfo>,
+}
+
+/// What went wrong, with the same stable code as plain error responses
+#[derive(Serialize, Debug)]
+struct ErrorInfo {
+    code: &'static str,
+    message: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Meta {
+    /// Whether the data was served from a cache instead of being fetched
+    pub cached: bool,
+    /// When the data was last fetched from upstream
+    pub updated: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct EnvelopeQuery {
+    #[serde(default)]
+    envelope: bool,
+}
+
+/// Whether the client asked for an envelope via `?envelope=true` or the `Accept` profile
+pub fn requested(req: &Request) -> bool {
+    let flag = req
+        .params::<EnvelopeQuery>()
+        .is_ok_and(|query| query.envelope);
+
+    let profile = req
+        .headers()
+        .get_all(poem::http::header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.contains(PROFILE));
+
+    flag || profile
+}
+
+impl<T: Serialize> Envelope<T> {
+    pub fn data(data: T, meta: Meta) -> Self {
+        Self {
+            data: Some(data),
+            meta: Some(meta),
+            error: None,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// JSON response that is wrapped in an envelope if there is metadata for one
+pub fn json<T: Serialize + Send>(data: T, meta: Option<Meta>) -> Response {
+    match meta {
+        Some(meta) => Json(Envelope::data(data, meta)).into_response(),
+        None => Json(data).into_response(),
+    }
+}
+
+/// Error response in the envelope shape, with the status and headers of the plain one
+pub fn error(e: &Error) -> Response {
+    let envelope = Envelope::<()> {
+        data: None,
+        meta: None,
+        error: Some(ErrorInfo {
+            code: e.code(),
+            message: e.to_string(),
+        }),
+    };
+
+    let mut response = Response::builder()
+        .status(e.status())
+        .content_type("application/json")
+        .body(envelope.to_json());
+    e.add_headers(&mut response);
+    response
+}
@@ -0,0 +1,127 @@
+use poem::{web::Json, IntoResponse, Response};
+use reqwest::{header, StatusCode};
+use serde::Serialize;
+use std::{sync::Arc, time::Duration};
+
+/// Everything that can go wrong while answering a request, mostly while getting data from
+/// Space-Track
+///
+/// Cheap to clone so concurrent requests waiting on the same fetch all get the error.
+#[derive(thiserror::Error, Debug, Clone)]
+pub enum Error {
+    #[error("Space-Track request failed: {0}")]
+    Upstream(Arc<reqwest::Error>),
+    #[error("Space-Track took too long to respond: {0}")]
+    Timeout(Arc<reqwest::Error>),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("Space-Track did not accept the login: {0}")]
+    Unauthorized(String),
+    /// Space-Track answered, but not with the data we asked for
+    #[error("Space-Track sent an unusable response: {0}")]
+    InvalidResponse(String),
+    #[error("Space-Track rate limit reached, retry in {}s", retry_after.as_secs())]
+    RateLimited { retry_after: Duration },
+    #[error("{0}")]
+    BadRequest(String),
+    /// The client didn't prove it may do what it asked for
+    #[error("{0}")]
+    Unauthenticated(String),
+    #[error("{0}")]
+    Propagation(String),
+    #[error("{0}")]
+    Internal(String),
+}
+
+/// What clients get as the body of an error response
+#[derive(Serialize)]
+struct ErrorBody {
+    error: &'static str,
+    message: String,
+}
+
+impl Error {
+    /// Stable identifier clients can match on instead of the message
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Upstream(_) => "upstream_error",
+            Self::Timeout(_) => "upstream_timeout",
+            Self::NotFound(_) => "not_found",
+            Self::Unauthorized(_) => "upstream_unauthorized",
+            Self::InvalidResponse(_) => "upstream_invalid_response",
+            Self::RateLimited { .. } => "rate_limited",
+            Self::BadRequest(_) => "bad_request",
+            Self::Unauthenticated(_) => "unauthenticated",
+            Self::Propagation(_) => "propagation_failed",
+            Self::Internal(_) => "internal",
+        }
+    }
+
+    /// Whether the request may well succeed if tried again later, unlike errors that would just
+    /// keep coming back
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::Upstream(_) | Self::Timeout(_) | Self::RateLimited { .. }
+        )
+    }
+
+    /// Status to answer a request with that failed because of this error
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Upstream(_) | Self::InvalidResponse(_) => StatusCode::BAD_GATEWAY,
+            Self::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            // Our credentials are broken, which is nothing the client can do anything about
+            Self::Unauthorized(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthenticated(_) => StatusCode::UNAUTHORIZED,
+            Self::Propagation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Adds headers telling the client when to try again or how to authenticate
+    pub fn add_headers(&self, response: &mut Response) {
+        match self {
+            Self::RateLimited { retry_after } => {
+                // Rounded up so clients don't come back a moment too early
+                let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                response
+                    .headers_mut()
+                    .insert(header::RETRY_AFTER, secs.into());
+            }
+            Self::Unauthenticated(_) => {
+                response.headers_mut().insert(
+                    header::WWW_AUTHENTICATE,
+                    header::HeaderValue::from_static("Bearer"),
+                );
+            }
+            _ => {}
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            Self::Timeout(Arc::new(e))
+        } else {
+            Self::Upstream(Arc::new(e))
+        }
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            error: self.code(),
+            message: self.to_string(),
+        };
+
+        let mut response = Json(body).with_status(self.status()).into_response();
+        self.add_headers(&mut response);
+        response
+    }
+}
@@ -0,0 +1,135 @@
+use crate::{de::deserialize_number_from_string, NoradId};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+use sgp4::{Classification, Elements};
+
+/// A single element set as returned by Space-Track's `gp` class (OMM keywords)
+#[derive(Deserialize, Debug, Clone)]
+#[serde(remote = "Self", rename_all = "SCREAMING_SNAKE_CASE")]
+pub struct GpRecord {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub norad_cat_id: NoradId,
+    pub object_name: Option<String>,
+    pub object_id: Option<String>,
+    pub epoch: NaiveDateTime,
+
+    /// Revolutions per day
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub mean_motion: f64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub eccentricity: f64,
+    /// Degrees
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub inclination: f64,
+    /// Degrees
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub ra_of_asc_node: f64,
+    /// Degrees
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub arg_of_pericenter: f64,
+    /// Degrees
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub mean_anomaly: f64,
+
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub bstar: f64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub mean_motion_dot: f64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub mean_motion_ddot: f64,
+
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub ephemeris_type: u8,
+    pub classification_type: String,
+    #[serde(
+        rename = "ELEMENT_SET_NO",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub element_set_number: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub rev_at_epoch: u64,
+
+    /// The record exactly as Space-Track sent it, numbers still encoded as strings
+    #[serde(skip)]
+    raw: Map<String, Value>,
+}
+
+impl<'de> Deserialize<'de> for GpRecord {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = Value::Object(Map::deserialize(deserializer)?);
+        let mut record = GpRecord::deserialize(&raw).map_err(D::Error::custom)?;
+        if let Value::Object(raw) = raw {
+            record.raw = raw;
+        }
+        Ok(record)
+    }
+}
+
+// Serializing hands back the upstream representation so clients see exactly what Space-Track served
+impl Serialize for GpRecord {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.raw.serialize(serializer)
+    }
+}
+
+impl GpRecord {
+    /// Space-Track epochs are always UTC even though the OMM omits the offset
+    pub fn epoch_utc(&self) -> DateTime<Utc> {
+        self.epoch.and_utc()
+    }
+
+    pub fn to_elements(&self) -> Elements {
+        Elements {
+            object_name: self.object_name.clone(),
+            international_designator: self.object_id.clone(),
+            norad_id: self.norad_cat_id as u64,
+            classification: match self.classification_type.as_str() {
+                "C" => Classification::Classified,
+                "S" => Classification::Secret,
+                _ => Classification::Unclassified,
+            },
+            datetime: self.epoch,
+            mean_motion_dot: self.mean_motion_dot,
+            mean_motion_ddot: self.mean_motion_ddot,
+            drag_term: self.bstar,
+            element_set_number: self.element_set_number,
+            inclination: self.inclination,
+            right_ascension: self.ra_of_asc_node,
+            eccentricity: self.eccentricity,
+            argument_of_perigee: self.arg_of_pericenter,
+            mean_anomaly: self.mean_anomaly,
+            mean_motion: self.mean_motion,
+            revolution_number: self.rev_at_epoch,
+            ephemeris_type: self.ephemeris_type,
+        }
+    }
+
+    /// Renders the record the way Space-Track serves it, as a single-element JSON array
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&[self]).expect("GP record should serialize")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ISS: &str = include_str!("fixtures/iss_gp.json");
+
+    #[test]
+    fn json_passes_upstream_representation_through() {
+        let records: Vec<GpRecord> = serde_json::from_str(ISS).unwrap();
+        assert_eq!(records[0].norad_cat_id, 25544);
+        assert_eq!(records[0].arg_of_pericenter, 130.536);
+        assert_eq!(records[0].to_json(), ISS.trim_end());
+    }
+
+    #[test]
+    fn persisted_records_deserialize_again() {
+        let records: Vec<GpRecord> = serde_json::from_str(ISS).unwrap();
+        let persisted = serde_json::to_string(&records).unwrap();
+        let restored: Vec<GpRecord> = serde_json::from_str(&persisted).unwrap();
+        assert_eq!(restored[0].to_json(), ISS.trim_end());
+    }
+}
@@ -0,0 +1,36 @@
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tracing::warn;
+
+/// Lock access that carries on after another thread panicked while holding the lock
+///
+/// Everything behind our locks is either cached or refetched periodically, so a request
+/// that panicked halfway through shouldn't take every later one down with it.
+pub trait Recover<T: ?Sized> {
+    fn lock_or_recover(&self) -> MutexGuard<'_, T>;
+}
+
+pub trait RecoverRw<T: ?Sized> {
+    fn read_or_recover(&self) -> RwLockReadGuard<'_, T>;
+    fn write_or_recover(&self) -> RwLockWriteGuard<'_, T>;
+}
+
+impl<T: ?Sized> Recover<T> for Mutex<T> {
+    fn lock_or_recover(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(recover)
+    }
+}
+
+impl<T: ?Sized> RecoverRw<T> for RwLock<T> {
+    fn read_or_recover(&self) -> RwLockReadGuard<'_, T> {
+        self.read().unwrap_or_else(recover)
+    }
+
+    fn write_or_recover(&self) -> RwLockWriteGuard<'_, T> {
+        self.write().unwrap_or_else(recover)
+    }
+}
+
+fn recover<G>(e: PoisonError<G>) -> G {
+    warn!("Recovering a lock that was poisoned by a panic");
+    e.into_inner()
+}
@@ -1,23 +1,100 @@
-use api::SpaceTrackClient;
-use perturbation::PerturbationCache;
+use access_log::AccessLog;
+use api::{SpaceTrackClient, UpstreamClient};
+use auth::{Admin, AdminToken};
+use cache::CachedData;
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use client_ip::TrustProxy;
+use config::ConfigError;
+use envelope::{Envelope, Meta};
+use error::Error;
+use futures_util::{stream, StreamExt};
+use gp::GpRecord;
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use perturbation::{Format, PerturbationCache};
 use poem::{
+    error::{ParsePathError, ParseQueryError},
     get, handler,
     listener::TcpListener,
     middleware::{AddData, Cors},
-    web::{Data, Json, Path, Query},
-    EndpointExt, Response, Route, Server,
+    post,
+    web::{websocket::WebSocket, Data, Json, Path, Query},
+    Body, EndpointExt, IntoResponse, Request, Response, ResponseBuilder, Route, Server,
 };
-use reqwest::{Method, StatusCode};
-use satellites::{ObjectType, Satellite, SatelliteDatabase};
-use serde::Deserialize;
-use std::sync::Arc;
+use propagation::{Geodetic, LookAngles, Observer, State};
+use reqwest::{header, Method, StatusCode};
+use satellites::{
+    Bounds, CatalogOptions, CollapsedResult, ObjectType, Regime, Satellite, SatelliteDatabase,
+    SearchOptions, SearchPage, SearchResult,
+};
+use serde::{Deserialize, Serialize};
+use sgp4::Elements;
+use shutdown::Watchdog;
+use std::{collections::BTreeMap, num::NonZeroUsize, path::PathBuf, sync::Arc, time::Duration};
+use tracing::{error, info};
+use tracing_subscriber::EnvFilter;
 
+mod access_log;
 mod api;
+mod auth;
+mod cache;
+mod client_ip;
+mod config;
+mod de;
+mod envelope;
+mod error;
+mod gp;
+mod locks;
+#[cfg(test)]
+mod mock;
 mod perturbation;
+mod propagation;
+mod render;
 mod satellites;
+mod shutdown;
+mod tle;
+mod track;
 
 type NoradId = usize;
 
+const DEFAULT_BIND: &str = "0.0.0.0:3000";
+
+// The satcat changes daily as objects decay and new launches are catalogued
+const DEFAULT_CATALOG_UPDATE_INTERVAL: Duration = Duration::from_secs(60 * 60 * 24);
+const DEFAULT_MAX_CATALOG_AGE: Duration = Duration::from_secs(60 * 60 * 48);
+// Space-Track publishes new GP data every few hours at most
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(60 * 60 * 4);
+// Keeps clients from sending every request upstream, or from getting data that is days old
+const MIN_REQUESTED_MAX_AGE: Duration = Duration::from_secs(60 * 5);
+const MAX_REQUESTED_MAX_AGE: Duration = Duration::from_secs(60 * 60 * 24);
+// Data fetched this recently is as fresh as a forced refresh would make it
+const REFRESH_MIN_AGE: Duration = Duration::from_secs(10);
+// Entries up to this age are served while refreshing
+const DEFAULT_MAX_STALE: Duration = Duration::from_secs(60 * 60 * 6);
+// Enough for every active object while keeping ID crawlers from growing the cache without bound
+const DEFAULT_CACHE_CAPACITY: NonZeroUsize = match NonZeroUsize::new(10_000) {
+    Some(capacity) => capacity,
+    None => unreachable!(),
+};
+const DEFAULT_PREWARM_TOP_K: usize = 50;
+const DEFAULT_PREWARM_MARGIN: Duration = Duration::from_secs(60 * 10);
+// Draining and flushing together fit into the 30s grace period most orchestrators give before
+// killing the process
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(20);
+const DEFAULT_SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Keeps the upstream query URL and the response to a reasonable size
+const MAX_BATCH_IDS: usize = 500;
+const MAX_RANGE_IDS: usize = 1000;
+const DEFAULT_SEARCH_LIMIT: usize = 20;
+const MAX_SEARCH_LIMIT: usize = 200;
+const MAX_EPHEMERIS_TIMES: usize = 1000;
+const DEFAULT_TRACK_INTERVAL: Duration = Duration::from_secs(5);
+const MIN_TRACK_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_TRACK_INTERVAL: Duration = Duration::from_secs(60);
+const CATALOG_PAGE_SIZE: usize = 1000;
+const DEFAULT_YEAR_LIMIT: usize = 100;
+const MAX_YEAR_LIMIT: usize = CATALOG_PAGE_SIZE;
+
 const DEFAULT_OBJECT_TYPES: &[ObjectType] = &[
     ObjectType::Payload,
     ObjectType::RocketBody,
@@ -27,43 +104,1303 @@ const DEFAULT_OBJECT_TYPES: &[ObjectType] = &[
 #[derive(Deserialize, Debug)]
 struct SearchQuery {
     q: String,
+    #[serde(default)]
+    collapse: bool,
+    prefer: Option<SearchPreference>,
+    /// Search the full catalog instead of only active payloads, rocket bodies and unknown objects
+    #[serde(default)]
+    all: bool,
+    #[serde(default)]
+    include_decayed: bool,
+    fields: Option<SearchFields>,
+    /// Comma separated object types to search instead of the defaults
+    types: Option<String>,
+    /// Space-Track owner abbreviation, unknown ones simply match nothing
+    country: Option<String>,
+    regime: Option<Regime>,
+    /// km, perigee and apogee both have to lie within
+    min_alt: Option<f64>,
+    max_alt: Option<f64>,
+    /// Degrees
+    min_incl: Option<f64>,
+    max_incl: Option<f64>,
+    /// Inclusive launch date range as `YYYY-MM-DD`
+    launched_after: Option<NaiveDate>,
+    launched_before: Option<NaiveDate>,
+    /// Only objects with a perigee low enough to re-enter soon
+    #[serde(default)]
+    reentry_soon: bool,
+    limit: Option<usize>,
+    #[serde(default)]
+    offset: usize,
+    #[serde(default)]
+    format: SearchFormat,
+}
+
+#[derive(Deserialize, Debug)]
+struct SuggestQuery {
+    q: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum SearchFormat {
+    #[default]
+    Json,
+    /// Main catalog columns with a header row, for spreadsheets and other tooling
+    Csv,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum SearchFields {
+    /// Only the NORAD IDs, for clients that keep their own copy of the catalog
+    Id,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum SearchPreference {
+    Payloads,
+}
+
+#[derive(Deserialize, Debug)]
+struct CurrentQuery {
+    format: Option<Format>,
+    /// Seconds, for clients that need fresher or can make do with older data than we'd serve
+    max_age: Option<u64>,
+    /// Ignore the cached data and fetch it again
+    #[serde(default)]
+    refresh: bool,
+}
+
+impl CurrentQuery {
+    /// Forcing a refresh is reserved for admins as it always costs an upstream query
+    fn max_age(&self, admin: bool) -> Result<Option<Duration>, Error> {
+        if self.refresh {
+            if !admin {
+                return Err(Error::Unauthenticated(
+                    "refreshing needs a valid admin token".into(),
+                ));
+            }
+
+            return Ok(Some(REFRESH_MIN_AGE));
+        }
+
+        Ok(self.max_age.map(|secs| {
+            Duration::from_secs(secs).clamp(MIN_REQUESTED_MAX_AGE, MAX_REQUESTED_MAX_AGE)
+        }))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct BatchQuery {
+    ids: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct RangeQuery {
+    start: NoradId,
+    /// Inclusive
+    end: NoradId,
+}
+
+#[derive(Deserialize, Debug)]
+struct TleQuery {
+    format: Option<Format>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CatalogQuery {
+    types: Option<String>,
+    decayed: Option<bool>,
+    launch_year: Option<i32>,
+    decay_year: Option<i32>,
+    /// Everything after `offset` if not given
+    limit: Option<usize>,
+    #[serde(default)]
+    offset: usize,
+}
+
+#[derive(Deserialize, Debug)]
+struct YearQuery {
+    launch_year: Option<i32>,
+    decay_year: Option<i32>,
+    limit: Option<usize>,
+    #[serde(default)]
+    offset: usize,
+}
+
+#[derive(Serialize, Debug)]
+struct Health {
+    status: &'static str,
+    catalog_updated: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    catalog_updated: Option<DateTime<Utc>>,
+    catalog_entries: usize,
+}
+
+#[derive(Serialize)]
+struct Stats {
+    catalog_entries: usize,
+    catalog_updated: Option<DateTime<Utc>>,
+    gp_cache: perturbation::Stats,
+}
+
+#[derive(Deserialize, Debug)]
+struct DumpQuery {
+    /// NORAD ID to continue after, as handed out in `X-GP-Next`
+    after: Option<NoradId>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct CachedEntry<'a> {
+    norad_id: NoradId,
+    /// Seconds since the data was fetched from upstream
+    age: u64,
+    data: &'a GpRecord,
+}
+
+#[derive(Serialize)]
+struct CatalogRefresh {
+    catalog_entries: usize,
+    catalog_updated: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PropagateQuery {
+    /// Comma separated times to propagate to, defaults to now
+    t: Option<String>,
+    // Optional observer to compute look angles for
+    lat: Option<f64>,
+    lon: Option<f64>,
+    /// Metres above the ellipsoid
+    alt: Option<f64>,
+}
+
+impl PropagateQuery {
+    fn observer(&self) -> Result<Option<Observer>, String> {
+        match (self.lat, self.lon) {
+            (Some(lat), Some(lon)) => {
+                Observer::new(lat, lon, self.alt.unwrap_or_default()).map(Some)
+            }
+            (None, None) if self.alt.is_none() => Ok(None),
+            _ => Err("an observer needs both lat and lon".into()),
+        }
+    }
+
+    fn times(&self) -> Result<Vec<DateTime<Utc>>, String> {
+        let Some(times) = &self.t else {
+            return Ok(vec![Utc::now()]);
+        };
+
+        let times = times
+            .split(',')
+            .map(|t| {
+                t.trim()
+                    .parse()
+                    .map_err(|e| format!("invalid time '{t}': {e}"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if times.len() > MAX_EPHEMERIS_TIMES {
+            return Err(format!(
+                "at most {MAX_EPHEMERIS_TIMES} times can be propagated at once"
+            ));
+        }
+
+        Ok(times)
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct Propagation {
+    #[serde(flatten)]
+    state: State,
+    geodetic: Geodetic,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    look_angles: Option<LookAngles>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TrackQuery {
+    /// Seconds between position updates
+    interval: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct VisibleQuery {
+    lat: f64,
+    lon: f64,
+    /// Metres above the ellipsoid
+    alt: Option<f64>,
+    sort: Option<VisibleSort>,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum VisibleSort {
+    Range,
+    #[default]
+    Elevation,
+}
+
+#[derive(Serialize, Debug)]
+struct VisibleSatellite {
+    norad_id: NoradId,
+    name: Option<String>,
+    #[serde(flatten)]
+    look_angles: LookAngles,
+}
+
+fn parse_object_types(list: &str) -> Result<Vec<ObjectType>, String> {
+    list.split(',').map(str::parse).collect()
+}
+
+/// Starts a response annotated with where the cached data came from and how old it is
+fn cached_response<T>(cached: &CachedData<T>) -> ResponseBuilder {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("X-Cache", cached.disposition().as_str())
+        // Picked up by the access log
+        .extension(cached.disposition())
+        .header(header::AGE, cached.age.as_secs())
+        // Downstream caches can keep the data for as long as we would
+        .header(
+            header::CACHE_CONTROL,
+            format!("max-age={}", cached.ttl().as_secs()),
+        )
+}
+
+#[handler]
+async fn current(
+    req: &Request,
+    Path(id): Path<usize>,
+    q: Query<CurrentQuery>,
+    admin: Option<Admin>,
+    cache: Data<&PerturbationCache>,
+    db: Data<&SatelliteDatabase>,
+) -> Response {
+    let format = q.format.unwrap_or(Format::Json);
+    let max_age = match q.max_age(admin.is_some()) {
+        Ok(max_age) => max_age,
+        Err(e) => return e.into_response(),
+    };
+
+    current_response(req, &cache, &db, id, format, max_age).await
+}
+
+#[handler]
+async fn current_batch(
+    q: Query<BatchQuery>,
+    cache: Data<&PerturbationCache>,
+    db: Data<&SatelliteDatabase>,
+) -> Response {
+    let mut ids = match q
+        .ids
+        .split(',')
+        .map(|id| id.trim().parse::<NoradId>())
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(ids) => ids,
+        Err(e) => {
+            return Error::BadRequest(format!("invalid NORAD ID in list: {e}")).into_response()
+        }
+    };
+
+    ids.sort_unstable();
+    ids.dedup();
+
+    if ids.len() > MAX_BATCH_IDS {
+        return Error::BadRequest(format!(
+            "at most {MAX_BATCH_IDS} IDs are allowed per request"
+        ))
+        .into_response();
+    }
+
+    let catalogued = match catalogued(&db, ids.iter().copied()) {
+        Ok(catalogued) => catalogued,
+        Err(e) => return e.into_response(),
+    };
+    let records = cache.get_or_fetch_many(&catalogued).await;
+
+    // Objects we have no data for are explicitly null so clients can tell them apart
+    let body = ids
+        .iter()
+        .map(|id| (*id, records.get(id).map(|record| &**record)))
+        .collect::<BTreeMap<_, _>>();
+
+    Json(body).into_response()
+}
+
+#[handler]
+async fn current_range(
+    q: Query<RangeQuery>,
+    cache: Data<&PerturbationCache>,
+    db: Data<&SatelliteDatabase>,
+) -> Response {
+    if q.end < q.start {
+        return Error::BadRequest("end of the range lies before its start".into()).into_response();
+    }
+
+    if q.end - q.start >= MAX_RANGE_IDS {
+        return Error::BadRequest(format!("at most {MAX_RANGE_IDS} IDs are allowed per range"))
+            .into_response();
+    }
+
+    let catalogued = match catalogued(&db, q.start..=q.end) {
+        Ok(catalogued) => catalogued,
+        Err(e) => return e.into_response(),
+    };
+    let records = cache.get_or_fetch_range(&catalogued).await;
+
+    // Unlike a list of IDs, ranges usually contain gaps that don't need to be spelled out
+    let body = (q.start..=q.end)
+        .filter_map(|id| records.get(&id).map(|record| &**record))
+        .collect::<Vec<_>>();
+
+    Json(body).into_response()
+}
+
+/// The IDs that are in the catalog, failing on the first one that could never have GP data
+///
+/// Uncatalogued objects are only left out, so they are answered without a query to Space-Track.
+fn catalogued(
+    db: &SatelliteDatabase,
+    ids: impl IntoIterator<Item = NoradId>,
+) -> Result<Vec<NoradId>, Error> {
+    let mut catalogued = Vec::new();
+
+    for id in ids {
+        match db.check_id(id) {
+            Ok(()) => catalogued.push(id),
+            Err(Error::NotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(catalogued)
+}
+
+#[handler]
+async fn current_by_designator(
+    req: &Request,
+    Path(designator): Path<String>,
+    q: Query<CurrentQuery>,
+    admin: Option<Admin>,
+    cache: Data<&PerturbationCache>,
+    db: Data<&SatelliteDatabase>,
+) -> Response {
+    let envelope = envelope::requested(req);
+    let format = q.format.unwrap_or(Format::Json);
+    let max_age = match q.max_age(admin.is_some()) {
+        Ok(max_age) => max_age,
+        Err(e) => return e.into_response(),
+    };
+
+    match db.find_by_designator(&designator) {
+        Some(id) => current_response(req, &cache, &db, id, format, max_age).await,
+        None => {
+            let e = Error::NotFound(format!("no catalog entry with designator {designator}"));
+            fail(e, envelope)
+        }
+    }
+}
+
+async fn current_response(
+    req: &Request,
+    cache: &PerturbationCache,
+    db: &SatelliteDatabase,
+    id: NoradId,
+    format: Format,
+    max_age: Option<Duration>,
+) -> Response {
+    // Envelopes only make sense around JSON
+    let envelope = envelope::requested(req) && format == Format::Json;
+
+    if let Err(e) = db.check_id(id) {
+        return fail(e, envelope);
+    }
+
+    let cached = match cache.get_or_fetch(id, max_age).await {
+        Ok(cached) => cached,
+        Err(e) => return fail(e, envelope),
+    };
+
+    let epoch = cached.data.epoch_utc();
+    // How long ago the elements were determined, which is what matters for their accuracy
+    let epoch_age = (Utc::now() - epoch).num_seconds() as f64 / 3600.0;
+
+    let (response, body, etag) = if envelope {
+        let meta = Meta {
+            cached: cached.hit,
+            updated: Some(Utc::now() - cached.age),
+        };
+
+        // Carries the response metadata, so there is nothing stable to tag
+        let body = Envelope::data([&*cached.data], meta).to_json().into();
+        (cached_response(&cached), body, None)
+    } else if let Some(output) = cache.render(&cached, format) {
+        (cached_response(&cached), output.body, Some(output.etag))
+    } else {
+        // Formats we can't render ourselves come from upstream and are cached on their own, as
+        // fresh as the GP data they go with
+        match cache.get_or_fetch_formatted(id, format, max_age).await {
+            Ok(formatted) => (
+                cached_response(&formatted),
+                formatted.data.body.clone(),
+                Some(formatted.data.etag.clone()),
+            ),
+            Err(e) => return fail(e, envelope),
+        }
+    };
+
+    let mut response = response
+        .header(
+            "X-GP-Epoch",
+            epoch.to_rfc3339_opts(SecondsFormat::Millis, true),
+        )
+        .header("X-GP-Age-Hours", format!("{epoch_age:.1}"))
+        .content_type(format.content_type());
+
+    if let Some(etag) = etag {
+        if if_none_match(req, &etag) {
+            return response
+                .status(StatusCode::NOT_MODIFIED)
+                .header(header::ETAG, etag)
+                .finish();
+        }
+
+        response = response.header(header::ETAG, etag);
+    }
+
+    response.body(body)
+}
+
+/// Error response in the envelope shape if the client asked for one
+fn fail(e: Error, envelope: bool) -> Response {
+    if envelope {
+        envelope::error(&e)
+    } else {
+        e.into_response()
+    }
+}
+
+/// Whether the client already has the representation with the given entity tag
+fn if_none_match(req: &Request, etag: &str) -> bool {
+    req.headers()
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        // Weak comparison, a weak tag for the same body is just as good here
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag)
 }
 
 #[handler]
-async fn current(Path(id): Path<usize>, cache: Data<&PerturbationCache>) -> Response {
-    match cache.get_or_fetch(id).await {
-        Ok(data) => Response::builder().status(StatusCode::OK).body(data),
-        Err(e) => Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body(e.to_string()),
+async fn current_tle(
+    Path(id): Path<usize>,
+    q: Query<TleQuery>,
+    cache: Data<&PerturbationCache>,
+    db: Data<&SatelliteDatabase>,
+) -> Response {
+    if let Err(e) = db.check_id(id) {
+        return e.into_response();
+    }
+
+    let format = q.format.unwrap_or(Format::ThreeLine);
+    if !matches!(format, Format::Tle | Format::ThreeLine) {
+        return Error::BadRequest(format!(
+            "format {format} is not a TLE format, expected tle or 3le"
+        ))
+        .into_response();
+    }
+
+    match cache.get_or_fetch(id, None).await {
+        Ok(cached) => cached_response(&cached)
+            .content_type(format.content_type())
+            .body(
+                cache
+                    .render(&cached, format)
+                    .map(|output| output.body)
+                    .unwrap_or_default(),
+            ),
+        Err(e) => e.into_response(),
     }
 }
 
 #[handler]
-async fn search(q: Query<SearchQuery>, db: Data<&SatelliteDatabase>) -> Json<Vec<Satellite>> {
-    Json(db.search(&q.q, DEFAULT_OBJECT_TYPES))
+async fn tle_latest(
+    Path(id): Path<usize>,
+    cache: Data<&PerturbationCache>,
+    db: Data<&SatelliteDatabase>,
+) -> Response {
+    if let Err(e) = db.check_id(id) {
+        return e.into_response();
+    }
+
+    match cache.get_or_fetch_tle_latest(id).await {
+        Ok(cached) => cached_response(&cached)
+            .content_type("text/plain")
+            .body(cached.data.to_string()),
+        Err(e) => e.into_response(),
+    }
+}
+
+#[handler]
+async fn search(req: &Request, q: Query<SearchQuery>, db: Data<&SatelliteDatabase>) -> Response {
+    // Envelopes only make sense around JSON
+    let envelope = envelope::requested(req) && q.format == SearchFormat::Json;
+
+    let types = match q.types.as_deref().map(parse_object_types).transpose() {
+        Ok(types) => types,
+        Err(e) => return fail(Error::BadRequest(e), envelope),
+    };
+
+    // Debris is left out of the active catalog
+    let inactive_types = types
+        .as_deref()
+        .is_some_and(|types| types.contains(&ObjectType::Debris));
+
+    let options = SearchOptions {
+        allowed_types: types.as_deref().unwrap_or(DEFAULT_OBJECT_TYPES),
+        prefer_payloads: q.prefer == Some(SearchPreference::Payloads),
+        // Decayed objects are only part of the full catalog
+        full_catalog: q.all || inactive_types || q.include_decayed,
+        include_decayed: q.include_decayed,
+        country: q.country.as_deref(),
+        regime: q.regime,
+        altitude: Bounds {
+            min: q.min_alt,
+            max: q.max_alt,
+        },
+        inclination: Bounds {
+            min: q.min_incl,
+            max: q.max_incl,
+        },
+        launched: Bounds {
+            min: q.launched_after,
+            max: q.launched_before,
+        },
+        reentry_soon: q.reentry_soon,
+        limit: q
+            .limit
+            .unwrap_or(DEFAULT_SEARCH_LIMIT)
+            .min(MAX_SEARCH_LIMIT),
+        offset: q.offset,
+    };
+
+    let meta = envelope.then(|| Meta {
+        cached: true,
+        updated: db.last_updated().map(DateTime::from),
+    });
+
+    if q.collapse {
+        let page = db.search_collapsed(&q.q, &options);
+        search_response(
+            page,
+            &q,
+            meta,
+            CollapsedResult::id,
+            CollapsedResult::satellite,
+        )
+    } else {
+        let page = db.search(&q.q, &options);
+        search_response(page, &q, meta, SearchResult::id, SearchResult::satellite)
+    }
+}
+
+/// Renders a page of search results in the requested format, with the number of matches across
+/// all pages in `X-Total-Count`
+fn search_response<T: Serialize + Send>(
+    page: SearchPage<T>,
+    q: &SearchQuery,
+    meta: Option<Meta>,
+    id: fn(&T) -> NoradId,
+    satellite: fn(&T) -> &Satellite,
+) -> Response {
+    let response = match (q.format, q.fields) {
+        (SearchFormat::Csv, _) => match satellites::to_csv(page.results.iter().map(satellite)) {
+            Ok(csv) => Response::builder().content_type("text/csv").body(csv),
+            Err(e) => return fail(Error::Internal(e.to_string()), meta.is_some()),
+        },
+        (SearchFormat::Json, Some(SearchFields::Id)) => {
+            envelope::json(page.results.iter().map(id).collect::<Vec<_>>(), meta)
+        }
+        (SearchFormat::Json, None) => envelope::json(page.results, meta),
+    };
+
+    response
+        .with_header("X-Total-Count", page.total)
+        .into_response()
+}
+
+#[handler]
+async fn satellite_by_id(Path(id): Path<usize>, db: Data<&SatelliteDatabase>) -> Response {
+    match db.get(id) {
+        Some(satellite) => Json(satellite).into_response(),
+        None => Error::NotFound(format!("no catalog entry for NORAD ID {id}")).into_response(),
+    }
+}
+
+#[handler]
+async fn suggest(q: Query<SuggestQuery>, db: Data<&SatelliteDatabase>) -> Json<Vec<Satellite>> {
+    Json(db.suggest(&q.q))
+}
+
+#[handler]
+async fn neighbors(Path(id): Path<usize>, db: Data<&SatelliteDatabase>) -> Response {
+    match db.neighbors(id) {
+        Some(neighbors) => Json(neighbors).into_response(),
+        None => Error::NotFound(format!("no orbital parameters for NORAD ID {id}")).into_response(),
+    }
+}
+
+#[handler]
+async fn catalog(q: Query<CatalogQuery>, db: Data<&SatelliteDatabase>) -> Response {
+    let types = match q.types.as_deref().map(parse_object_types).transpose() {
+        Ok(types) => types,
+        Err(e) => return Error::BadRequest(e).into_response(),
+    };
+
+    let ids = db.catalog(&CatalogOptions {
+        allowed_types: types.as_deref(),
+        decayed: q.decayed,
+        launch_year: q.launch_year,
+        decay_year: q.decay_year,
+        limit: q.limit.unwrap_or(usize::MAX),
+        offset: q.offset,
+    });
+    // Satellites are looked up a page at a time so a full dump never holds more than that in memory
+    let pages = ids
+        .chunks(CATALOG_PAGE_SIZE)
+        .map(<[_]>::to_vec)
+        .collect::<Vec<_>>();
+    let lines = stream::iter(pages).flat_map({
+        let db = db.clone();
+        move |page| {
+            stream::iter(db.get_many(&page).into_iter().map(|satellite| {
+                serde_json::to_vec(&satellite).map(|mut line| {
+                    line.push(b'\n');
+                    line
+                })
+            }))
+        }
+    });
+
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .content_type("application/x-ndjson");
+
+    if let Some(last_updated) = db.last_updated() {
+        response = response.header(header::LAST_MODIFIED, httpdate::fmt_http_date(last_updated));
+    }
+
+    response.body(Body::from_bytes_stream(lines))
+}
+
+/// Satellites launched or re-entered in a given year as a JSON array, a page at a time
+///
+/// Unlike the full dump this is always available, as it never returns more than a page.
+#[handler]
+async fn satellites_by_year(q: Query<YearQuery>, db: Data<&SatelliteDatabase>) -> Response {
+    if q.launch_year.is_none() && q.decay_year.is_none() {
+        return Error::BadRequest("expected a launch_year or decay_year".into()).into_response();
+    }
+
+    let ids = db.catalog(&CatalogOptions {
+        allowed_types: None,
+        decayed: None,
+        launch_year: q.launch_year,
+        decay_year: q.decay_year,
+        limit: q.limit.unwrap_or(DEFAULT_YEAR_LIMIT).min(MAX_YEAR_LIMIT),
+        offset: q.offset,
+    });
+
+    Json(db.get_many(&ids)).into_response()
+}
+
+/// Cached GP entries as NDJSON in the order of their IDs, without going upstream for anything
+///
+/// Pages through the cache when given a limit, `X-GP-Next` holds the ID to continue after and is
+/// empty once there are no more entries.
+#[handler]
+async fn current_all(
+    _admin: Admin,
+    q: Query<DumpQuery>,
+    cache: Data<&PerturbationCache>,
+) -> Response {
+    // Empty pages would never get anywhere
+    let limit = q.limit.map_or(usize::MAX, |limit| limit.max(1));
+    let (entries, next) = cache.cached_entries(q.after, limit);
+    let lines = stream::iter(entries.into_iter().map(|entry| {
+        let line = CachedEntry {
+            norad_id: entry.key,
+            age: entry.fetched.elapsed().unwrap_or_default().as_secs(),
+            data: &entry.data,
+        };
+
+        serde_json::to_vec(&line).map(|mut line| {
+            line.push(b'\n');
+            line
+        })
+    }));
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .content_type("application/x-ndjson")
+        .header(
+            "X-GP-Next",
+            next.map(|id| id.to_string()).unwrap_or_default(),
+        )
+        .body(Body::from_bytes_stream(lines))
+}
+
+#[handler]
+async fn health(db: Data<&SatelliteDatabase>) -> Json<Health> {
+    Json(Health {
+        status: if db.is_outdated() { "degraded" } else { "ok" },
+        catalog_updated: db.last_updated().map(DateTime::from),
+    })
+}
+
+/// Ready to serve once the catalog has been loaded
+#[handler]
+async fn ready(db: Data<&SatelliteDatabase>) -> Response {
+    let catalog_updated = db.last_updated();
+    let status = if catalog_updated.is_some() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    Json(Readiness {
+        ready: catalog_updated.is_some(),
+        catalog_updated: catalog_updated.map(DateTime::from),
+        catalog_entries: db.len(),
+    })
+    .with_status(status)
+    .into_response()
+}
+
+#[handler]
+async fn stats(
+    req: &Request,
+    db: Data<&SatelliteDatabase>,
+    cache: Data<&PerturbationCache>,
+) -> Response {
+    // Counted live, so there is nothing cached about it
+    let meta = envelope::requested(req).then_some(Meta {
+        cached: false,
+        updated: None,
+    });
+
+    let stats = Stats {
+        catalog_entries: db.len(),
+        catalog_updated: db.last_updated().map(DateTime::from),
+        gp_cache: cache.stats(),
+    };
+    envelope::json(stats, meta)
+}
+
+/// Updates the catalog right away instead of waiting for the next scheduled update
+#[handler]
+async fn refresh_catalog(_admin: Admin, db: Data<&SatelliteDatabase>) -> Response {
+    match db.update().await {
+        Ok(()) => Json(CatalogRefresh {
+            catalog_entries: db.len(),
+            catalog_updated: db.last_updated().map(DateTime::from),
+        })
+        .into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+#[handler]
+async fn metrics(handle: Data<&PrometheusHandle>) -> Response {
+    Response::builder()
+        .content_type("text/plain; version=0.0.4")
+        .body(handle.render())
+}
+
+#[handler]
+async fn propagate_tle(q: Query<PropagateQuery>, body: String) -> Response {
+    let elements = match propagation::parse_tle(&body) {
+        Ok(elements) => elements,
+        Err(e) => return Error::BadRequest(e.to_string()).into_response(),
+    };
+
+    propagation_response(&elements, &q)
+}
+
+#[handler]
+async fn propagate_cached(
+    Path(id): Path<usize>,
+    q: Query<PropagateQuery>,
+    cache: Data<&PerturbationCache>,
+    db: Data<&SatelliteDatabase>,
+) -> Response {
+    if let Err(e) = db.check_id(id) {
+        return e.into_response();
+    }
+
+    match cache.get_or_fetch(id, None).await {
+        Ok(cached) => propagation_response(&cached.data.to_elements(), &q),
+        Err(e) => e.into_response(),
+    }
+}
+
+fn propagation_response(elements: &Elements, q: &PropagateQuery) -> Response {
+    let observer = match q.observer() {
+        Ok(observer) => observer,
+        Err(e) => return Error::BadRequest(e).into_response(),
+    };
+
+    let times = match q.times() {
+        Ok(times) => times,
+        Err(e) => return Error::BadRequest(e).into_response(),
+    };
+
+    let propagations = times
+        .iter()
+        .map(|&time| {
+            let state = propagation::propagate(elements, time)?;
+            Ok(Propagation {
+                geodetic: state.geodetic(),
+                look_angles: observer.map(|observer| observer.look_angles(&state)),
+                state,
+            })
+        })
+        .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>();
+
+    match propagations {
+        // A single time keeps the response a plain object
+        Ok(mut propagations) if propagations.len() == 1 => {
+            Json(propagations.remove(0)).into_response()
+        }
+        Ok(propagations) => Json(propagations).into_response(),
+        Err(e) => Error::Propagation(e.to_string()).into_response(),
+    }
+}
+
+/// Websocket pushing the current position of the object every few seconds
+#[handler]
+async fn track_position(
+    Path(id): Path<usize>,
+    q: Query<TrackQuery>,
+    ws: WebSocket,
+    cache: Data<&PerturbationCache>,
+    db: Data<&SatelliteDatabase>,
+) -> Response {
+    if let Err(e) = db.check_id(id) {
+        return e.into_response();
+    }
+
+    // Fetched up front so failures still get a proper HTTP response
+    let cached = match cache.get_or_fetch(id, None).await {
+        Ok(cached) => cached,
+        Err(e) => return e.into_response(),
+    };
+
+    let interval = q.interval.map_or(DEFAULT_TRACK_INTERVAL, |secs| {
+        Duration::from_secs(secs).clamp(MIN_TRACK_INTERVAL, MAX_TRACK_INTERVAL)
+    });
+    let cache = cache.clone();
+
+    ws.on_upgrade(move |socket| track::stream_positions(socket, cache, id, cached, interval))
+        .into_response()
+}
+
+#[handler]
+async fn visible(q: Query<VisibleQuery>, cache: Data<&PerturbationCache>) -> Response {
+    let observer = match Observer::new(q.lat, q.lon, q.alt.unwrap_or_default()) {
+        Ok(observer) => observer,
+        Err(e) => return Error::BadRequest(e).into_response(),
+    };
+
+    let cached = match cache.get_or_fetch_leo_payloads().await {
+        Ok(cached) => cached,
+        Err(e) => return e.into_response(),
+    };
+
+    let records = cached.data.clone();
+    let sort = q.sort.unwrap_or_default();
+
+    // Propagating a few thousand objects would stall the executor
+    let visible = tokio::task::spawn_blocking(move || {
+        let now = Utc::now();
+        let mut visible = records
+            .iter()
+            .filter_map(|record| {
+                let state = propagation::propagate(&record.to_elements(), now).ok()?;
+                let look_angles = observer.look_angles(&state);
+
+                (look_angles.elevation > 0.0).then(|| VisibleSatellite {
+                    norad_id: record.norad_cat_id,
+                    name: record.object_name.clone(),
+                    look_angles,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        match sort {
+            VisibleSort::Range => {
+                visible.sort_by(|a, b| a.look_angles.range.total_cmp(&b.look_angles.range))
+            }
+            VisibleSort::Elevation => {
+                visible.sort_by(|a, b| b.look_angles.elevation.total_cmp(&a.look_angles.elevation))
+            }
+        }
+
+        visible
+    })
+    .await;
+
+    match visible {
+        Ok(visible) => cached_response(&cached)
+            .content_type("application/json")
+            .body(serde_json::to_string(&visible).unwrap_or_default()),
+        Err(e) => Error::Internal(e.to_string()).into_response(),
+    }
+}
+
+/// Origins from `GPCACHE_CORS_ORIGINS`, where none at all means any origin is allowed
+fn cors_origins() -> Vec<String> {
+    let origins: Vec<String> = std::env::var("GPCACHE_CORS_ORIGINS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|origin| !origin.is_empty())
+        .map(str::to_owned)
+        .collect();
+
+    if origins.iter().any(|origin| origin == "*") {
+        return Vec::new();
+    }
+
+    origins
+}
+
+/// NORAD IDs from `GPCACHE_WATCHLIST`, either listed right there or in the file it points to
+fn watchlist() -> Result<Vec<NoradId>, ConfigError> {
+    let Ok(value) = std::env::var("GPCACHE_WATCHLIST") else {
+        return Ok(Vec::new());
+    };
+
+    let is_list = value
+        .chars()
+        .all(|c| c.is_ascii_digit() || c == ',' || c.is_whitespace());
+    let list = if is_list {
+        value
+    } else {
+        std::fs::read_to_string(&value)
+            .map_err(|e| ConfigError(format!("Failed to read watchlist {value}: {e}")))?
+    };
+
+    list.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|id| !id.is_empty())
+        .map(|id| {
+            id.parse()
+                .map_err(|e| ConfigError(format!("Invalid NORAD ID '{id}' in watchlist: {e}")))
+        })
+        .collect()
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let client = Arc::new(SpaceTrackClient::from_env());
-    let cache = PerturbationCache::new(client.clone());
-    let db = SatelliteDatabase::new(client);
-    let cors = Cors::new().allow_methods([Method::GET, Method::OPTIONS]);
+    // Defaults to info, RUST_LOG overrides it
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::builder()
+                .with_default_directive(tracing::Level::INFO.into())
+                .from_env_lossy(),
+        )
+        .init();
 
-    // TODO Run this on a timer or smth
-    db.update().await?;
+    // Mostly configuration the operator has to fix, which is best shown as is
+    if let Err(e) = run().await {
+        error!("{e}");
+        std::process::exit(1);
+    }
 
-    let app = Route::new()
+    Ok(())
+}
+
+async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let metrics_handle = PrometheusBuilder::new().install_recorder()?;
+
+    let client: Arc<dyn UpstreamClient> = Arc::new(SpaceTrackClient::from_env()?);
+    let mut cache = PerturbationCache::new(
+        client.clone(),
+        config::env_secs("GPCACHE_MAX_AGE_SECS")?.unwrap_or(DEFAULT_MAX_AGE),
+        config::env_secs("GPCACHE_MAX_STALE_SECS")?.unwrap_or(DEFAULT_MAX_STALE),
+        config::env_var("GPCACHE_CACHE_CAPACITY")?.unwrap_or(DEFAULT_CACHE_CAPACITY),
+    );
+    if let Some(max_age) = config::env_secs("GPCACHE_MISSING_MAX_AGE_SECS")? {
+        cache = cache.with_missing_max_age(max_age);
+    }
+    let mut db = SatelliteDatabase::new(
+        client,
+        config::env_secs("GPCACHE_MAX_CATALOG_AGE_SECS")?.unwrap_or(DEFAULT_MAX_CATALOG_AGE),
+    );
+    if let Some(interval) = config::env_secs("GPCACHE_CATALOG_FULL_UPDATE_INTERVAL_SECS")? {
+        db = db.with_full_update_interval(interval);
+    }
+    if let Some(max_id) = config::env_var("GPCACHE_MAX_NORAD_ID")? {
+        db = db.with_max_id(max_id);
+    }
+    if let Some(perigee) = config::env_var("GPCACHE_REENTRY_PERIGEE_KM")? {
+        db = db.with_reentry_perigee(perigee);
+    }
+    cache = cache.with_catalog(db.clone());
+    let cors = cors_origins()
+        .into_iter()
+        .fold(Cors::new(), |cors, origin| cors.allow_origin(origin))
+        .allow_methods([Method::GET, Method::OPTIONS]);
+
+    let prewarm_top_k = config::env_var("GPCACHE_PREWARM_TOP_K")?.unwrap_or(DEFAULT_PREWARM_TOP_K);
+    let prewarm_margin =
+        config::env_secs("GPCACHE_PREWARM_MARGIN_SECS")?.unwrap_or(DEFAULT_PREWARM_MARGIN);
+
+    // Restored first so prewarming immediately refreshes whatever was hot before the restart
+    let cache_path = std::env::var("GPCACHE_CACHE_PATH").ok().map(PathBuf::from);
+    if let Some(path) = &cache_path {
+        cache = cache.load_from(path.clone());
+        tokio::spawn(cache.clone().persist());
+    }
+
+    if prewarm_top_k > 0 {
+        tokio::spawn(cache.clone().keep_warm(prewarm_top_k, prewarm_margin));
+    }
+
+    let watchlist = watchlist()?;
+    if !watchlist.is_empty() {
+        tokio::spawn(cache.clone().warm_up(watchlist, MAX_BATCH_IDS));
+    }
+
+    // Loaded in the background so probes can tell the process is alive but not ready yet
+    let catalog_update_interval = config::env_secs("GPCACHE_CATALOG_UPDATE_INTERVAL_SECS")?
+        .unwrap_or(DEFAULT_CATALOG_UPDATE_INTERVAL);
+    tokio::spawn(db.clone().keep_updated(catalog_update_interval));
+
+    let mut app = Route::new()
+        .at("/health", get(health))
+        .at("/ready", get(ready))
+        .at("/metrics", get(metrics))
+        .at("/stats", get(stats))
         .at("/search", get(search))
+        .at("/search/suggest", get(suggest))
+        .at("/current", get(current_batch))
+        .at("/current/range", get(current_range))
         .at("/current/:id", get(current))
-        .with(AddData::new(cache))
+        .at("/current/intl/:designator", get(current_by_designator))
+        .at("/tle/:id", get(current_tle))
+        .at("/tle-latest/:id", get(tle_latest))
+        .at("/propagate", post(propagate_tle))
+        .at("/propagate/:id", get(propagate_cached))
+        .at("/track/:id", get(track_position))
+        .at("/visible", get(visible))
+        .at("/satellite/:id", get(satellite_by_id))
+        .at("/satellites", get(satellites_by_year))
+        .at("/neighbors/:id", get(neighbors));
+
+    // Dumping the whole catalog is a large response, so operators have to opt in
+    if std::env::var("GPCACHE_ENABLE_CATALOG").is_ok_and(|v| v == "1" || v == "true") {
+        app = app.at("/catalog", get(catalog));
+    }
+
+    // Without a token nobody could use these anyway, so they aren't there at all
+    let admin_token = std::env::var("GPCACHE_ADMIN_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty())
+        .map(Arc::from);
+    if admin_token.is_some() {
+        app = app
+            .at("/satellite-db/refresh", post(refresh_catalog))
+            .at("/current/all", get(current_all));
+    }
+
+    // Only enable behind a reverse proxy, otherwise clients can pick their own address
+    let trust_proxy = std::env::var("GPCACHE_TRUST_PROXY").is_ok_and(|v| v == "1" || v == "true");
+
+    let app = app
+        // Malformed parameters get the same kind of body as every other error
+        .catch_error(|e: ParseQueryError| async move { Error::BadRequest(e.to_string()) })
+        .catch_error(|e: ParsePathError| async move { Error::BadRequest(e.to_string()) })
+        .with(AddData::new(AdminToken(admin_token)))
+        .with(AddData::new(cache.clone()))
         .with(AddData::new(db))
-        .with(cors);
+        .with(AddData::new(metrics_handle))
+        .with(cors)
+        .with(AccessLog)
+        .with(AddData::new(TrustProxy(trust_proxy)));
+
+    let shutdown_timeout =
+        config::env_secs("GPCACHE_SHUTDOWN_TIMEOUT_SECS")?.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT);
+    let flush_timeout = config::env_secs("GPCACHE_SHUTDOWN_FLUSH_TIMEOUT_SECS")?
+        .unwrap_or(DEFAULT_SHUTDOWN_FLUSH_TIMEOUT);
+    // Draining can take all of its time and still leave the flush enough of its own
+    let watchdog = Watchdog::arm(shutdown_timeout + flush_timeout);
+
+    let bind = config::cli_flag("bind")?
+        .or_else(|| std::env::var("GPCACHE_BIND").ok())
+        .unwrap_or_else(|| DEFAULT_BIND.into());
 
-    Server::new(TcpListener::bind("0.0.0.0:3000"))
-        .run(app)
+    Server::new(TcpListener::bind(bind))
+        .run_with_graceful_shutdown(app, shutdown::signal(), Some(shutdown_timeout))
         .await?;
 
+    if cache_path.is_some() {
+        watchdog.enter("flushing the cache snapshot");
+
+        if let Err(e) = cache.flush() {
+            error!(error = %e, "Failed to flush cache snapshot");
+        }
+    }
+
+    info!("Shut down cleanly");
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockClient;
+    use poem::{http::Uri, Endpoint};
+
+    const ISS: &str = include_str!("fixtures/iss_gp.json");
+    const SATCAT: &str = include_str!("fixtures/satcat.json");
+    const GP_PATH: &str = "/basicspacedata/query/class/gp/NORAD_CAT_ID/25544";
+    const CSV_PATH: &str = "/basicspacedata/query/class/gp/NORAD_CAT_ID/25544/format/csv";
+    const SATCAT_PATH: &str =
+        "/basicspacedata/query/class/satcat/orderby/NORAD_CAT_ID%20asc/emptyresult/show";
+
+    /// The routes under test, with the caches in front of `client` and a loaded catalog
+    async fn app(client: &Arc<MockClient>, max_age: Duration) -> impl Endpoint {
+        let db = SatelliteDatabase::new(client.clone(), Duration::from_secs(60));
+        db.update().await.unwrap();
+        let cache =
+            PerturbationCache::new(client.clone(), max_age, max_age, DEFAULT_CACHE_CAPACITY);
+
+        Route::new()
+            .at("/current/:id", get(current))
+            .at("/satellites", get(satellites_by_year))
+            .at("/search", get(search))
+            .catch_error(|e: ParseQueryError| async move { Error::BadRequest(e.to_string()) })
+            .with(AddData::new(AdminToken(None)))
+            .with(AddData::new(cache))
+            .with(AddData::new(db))
+    }
+
+    async fn send(app: &impl Endpoint, uri: &'static str) -> Response {
+        let request = Request::builder().uri(Uri::from_static(uri)).finish();
+        app.get_response(request).await
+    }
+
+    /// NORAD IDs in a JSON array of satellites
+    async fn ids(response: Response) -> Vec<u64> {
+        let body = response.into_body().into_string().await.unwrap();
+        serde_json::from_str::<Vec<serde_json::Value>>(&body)
+            .unwrap()
+            .iter()
+            .map(|satellite| satellite["NORAD_CAT_ID"].as_u64().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn formats_from_upstream_share_the_requested_max_age() {
+        let client = Arc::new(
+            MockClient::new()
+                .respond(SATCAT_PATH, SATCAT)
+                .respond(GP_PATH, ISS)
+                .respond(CSV_PATH, "NORAD_CAT_ID\n25544\n"),
+        );
+        let max_age = Duration::from_millis(50);
+        let app = app(&client, max_age).await;
+
+        let response = send(&app, "/current/25544?format=csv").await;
+        assert_eq!(response.headers()["X-Cache"], "MISS");
+        tokio::time::sleep(max_age).await;
+
+        // The requested max age covers both, so both are served while being refreshed
+        let response = send(&app, "/current/25544?format=csv&max_age=0").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["X-Cache"], "STALE");
+        assert_eq!(
+            response.into_body().into_string().await.unwrap(),
+            "NORAD_CAT_ID\n25544\n"
+        );
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(client.queries(GP_PATH), 2);
+        assert_eq!(client.queries(CSV_PATH), 2);
+    }
+
+    #[tokio::test]
+    async fn lists_satellites_by_year_a_page_at_a_time() {
+        let client = Arc::new(MockClient::new().respond(SATCAT_PATH, SATCAT));
+        let app = app(&client, Duration::from_secs(60)).await;
+
+        let launched = send(&app, "/satellites?launch_year=2019").await;
+        assert_eq!(ids(launched).await, [44713, 44714, 44720]);
+        let page = send(&app, "/satellites?launch_year=2019&limit=1&offset=1").await;
+        assert_eq!(ids(page).await, [44714]);
+        let decayed = send(&app, "/satellites?decay_year=2023").await;
+        assert_eq!(ids(decayed).await, [44720]);
+        let nothing = send(&app, "/satellites?launch_year=1957").await;
+        assert!(ids(nothing).await.is_empty());
+
+        for uri in ["/satellites?launch_year=soon", "/satellites"] {
+            assert_eq!(send(&app, uri).await.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn rate_limits_reach_the_client() {
+        let client = Arc::new(MockClient::new().respond(SATCAT_PATH, SATCAT).fail(
+            GP_PATH,
+            Error::RateLimited {
+                retry_after: Duration::from_secs(30),
+            },
+        ));
+        let app = app(&client, Duration::from_secs(60)).await;
+
+        let response = send(&app, "/current/25544").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "30");
+
+        // Enveloped errors carry the same code
+        let response = send(&app, "/current/25544?envelope=true").await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "30");
+        let body = response.into_body().into_string().await.unwrap();
+        let body = serde_json::from_str::<serde_json::Value>(&body).unwrap();
+        assert_eq!(body["error"]["code"], "rate_limited");
+        assert!(body["data"].is_null());
+    }
+
+    #[tokio::test]
+    async fn search_errors_are_enveloped_when_asked_for() {
+        let client = Arc::new(MockClient::new().respond(SATCAT_PATH, SATCAT));
+        let app = app(&client, Duration::from_secs(60)).await;
+
+        let response = send(&app, "/search?q=iss&types=moons&envelope=true").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response.into_body().into_string().await.unwrap();
+        let body = serde_json::from_str::<serde_json::Value>(&body).unwrap();
+        assert_eq!(body["error"]["code"], "bad_request");
+        assert!(body["data"].is_null());
+
+        let response = send(&app, "/search?q=iss&types=moons").await;
+        let body = response.into_body().into_string().await.unwrap();
+        let body = serde_json::from_str::<serde_json::Value>(&body).unwrap();
+        assert_eq!(body["error"], "bad_request");
+    }
+}
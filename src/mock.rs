@@ -0,0 +1,103 @@
+use crate::{
+    api::{Body, UpstreamClient},
+    error::Error,
+    locks::Recover,
+};
+use bytes::Bytes;
+use futures_util::{stream, StreamExt};
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+/// Stands in for Space-Track with canned responses or failures, keeping track of the queries it
+/// gets
+#[derive(Default)]
+pub struct MockClient {
+    responses: Mutex<HashMap<String, Result<Bytes, Error>>>,
+    queries: Mutex<Vec<String>>,
+    /// Time every query takes before its response comes in
+    delay: Duration,
+    /// Size of the chunks response bodies arrive in, all at once if not set
+    chunk_size: Option<usize>,
+}
+
+impl MockClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = Some(chunk_size);
+        self
+    }
+
+    /// Answers queries for `path` with `body` from now on
+    pub fn respond(self, path: impl Into<String>, body: impl Into<Bytes>) -> Self {
+        self.answer(path, Ok(body.into()));
+        self
+    }
+
+    /// Fails queries for `path` with `error` from now on, like a rejected session, a rate limit
+    /// or a timeout would
+    pub fn fail(self, path: impl Into<String>, error: Error) -> Self {
+        self.answer(path, Err(error));
+        self
+    }
+
+    /// Changes what queries for `path` get, e.g. to let upstream fail halfway through a test
+    pub fn answer(&self, path: impl Into<String>, response: Result<Bytes, Error>) {
+        self.responses
+            .lock_or_recover()
+            .insert(path.into(), response);
+    }
+
+    /// Number of queries for `path` so far
+    pub fn queries(&self, path: &str) -> usize {
+        self.queries
+            .lock_or_recover()
+            .iter()
+            .filter(|query| *query == path)
+            .count()
+    }
+}
+
+#[poem::async_trait]
+impl UpstreamClient for MockClient {
+    async fn query(&self, path: String) -> Result<Body, Error> {
+        self.queries.lock_or_recover().push(path.clone());
+        tokio::time::sleep(self.delay).await;
+
+        let Some(response) = self.responses.lock_or_recover().get(&path).cloned() else {
+            return Err(Error::InvalidResponse(format!(
+                "no mock response for {path}"
+            )));
+        };
+        let body = response?;
+
+        let chunk_size = self.chunk_size.unwrap_or(body.len()).max(1);
+        let chunks = (0..body.len())
+            .step_by(chunk_size)
+            .map(|start| Ok(body.slice(start..(start + chunk_size).min(body.len()))))
+            .collect::<Vec<_>>();
+
+        Ok(stream::iter(chunks).boxed())
+    }
+}
+
+/// An actual timeout, as there is no way to make up the `reqwest::Error` it carries
+pub async fn timeout() -> Error {
+    // Takes connections but never answers on them
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+
+    reqwest::Client::new()
+        .get(url)
+        .timeout(Duration::from_millis(10))
+        .send()
+        .await
+        .unwrap_err()
+        .into()
+}
@@ -1,55 +1,698 @@
-use crate::{api::SpaceTrackClient, NoradId};
+use crate::{
+    api::UpstreamClient,
+    cache::{Cache, CachedData, Freshness, Snapshot},
+    error::Error,
+    gp::GpRecord,
+    render::{Output, RenderCache},
+    satellites::{Regime, SatelliteDatabase},
+    NoradId,
+};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex},
-    time::{Duration, Instant},
+    fmt,
+    io::ErrorKind,
+    num::NonZeroUsize,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
 };
+use tracing::{debug, error, info, warn};
+
+// Short enough that newly catalogued objects show up soon after Space-Track has them
+const DEFAULT_MISSING_MAX_AGE: Duration = Duration::from_secs(60 * 15);
+const PREWARM_INTERVAL: Duration = Duration::from_secs(60);
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60 * 5);
+const QUERY_PATH: &str = "/basicspacedata/query/class/gp/NORAD_CAT_ID";
+const TLE_LATEST_PATH: &str = "/basicspacedata/query/class/tle_latest/ORDINAL/1/NORAD_CAT_ID";
+// Active payloads below ~128 min period with elements from the last ten days
+const LEO_PAYLOADS_PATH: &str = "/basicspacedata/query/class/gp/OBJECT_TYPE/PAYLOAD/DECAY_DATE/null-val/PERIOD/%3C128/EPOCH/%3Enow-10";
+
+/// Output formats of the Space-Track `gp` class
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Format {
+    Json,
+    Xml,
+    Tle,
+    ThreeLine,
+    Csv,
+}
 
-const MAX_AGE: Duration = Duration::from_secs(60 * 60 * 4);
-const QUERY_URL: &str = "https://www.space-track.org/basicspacedata/query/class/gp/NORAD_CAT_ID";
+/// Key for GP data that is fetched in a specific format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Formatted {
+    id: NoradId,
+    format: Format,
+}
+
+/// GP entry as written to the snapshot file
+#[derive(Serialize, Deserialize)]
+struct PersistedEntry<R> {
+    fetched: SystemTime,
+    accesses: u64,
+    record: R,
+}
+
+/// Snapshot of how the GP cache is doing
+#[derive(Serialize, Debug)]
+pub struct Stats {
+    pub entries: usize,
+    /// Lookups since startup that were answered from the cache
+    pub hits: usize,
+    /// Lookups since startup that had to go to Space-Track
+    pub misses: usize,
+}
 
 #[derive(Clone)]
 pub struct PerturbationCache {
-    entries: Arc<Mutex<HashMap<NoradId, (Instant, String)>>>,
-    client: Arc<SpaceTrackClient>,
+    entries: Cache<NoradId, GpRecord>,
+    // Kept apart from the GP data as it is the TLE verbatim rather than something we render
+    tle_latest: Cache<NoradId, String>,
+    // Formats we can't render from the GP data ourselves
+    formatted: Cache<Formatted, Output>,
+    // Bulk sets of GP data keyed by the name of the selection
+    bulk: Cache<&'static str, Vec<GpRecord>>,
+    // IDs Space-Track recently had no GP data for
+    missing: Cache<NoradId, ()>,
+    rendered: RenderCache,
+    hits: Arc<AtomicUsize>,
+    misses: Arc<AtomicUsize>,
+    /// File the GP entries are persisted to so they survive restarts
+    snapshot_path: Option<PathBuf>,
+    client: Arc<dyn UpstreamClient>,
 }
 
 impl PerturbationCache {
-    pub fn new(client: Arc<SpaceTrackClient>) -> Self {
+    /// Entries are refreshed once they are older than `max_age`, those younger than `max_stale`
+    /// are served right away while that happens. At most `capacity` objects are kept per kind of
+    /// data.
+    pub fn new(
+        client: Arc<dyn UpstreamClient>,
+        max_age: Duration,
+        max_stale: Duration,
+        capacity: NonZeroUsize,
+    ) -> Self {
         Self {
             client,
-            entries: Arc::new(Mutex::new(HashMap::new())),
+            entries: Cache::new(max_age)
+                .with_max_stale(max_stale)
+                .with_capacity(capacity),
+            tle_latest: Cache::new(max_age)
+                .with_max_stale(max_stale)
+                .with_capacity(capacity),
+            formatted: Cache::new(max_age)
+                .with_max_stale(max_stale)
+                .with_capacity(capacity),
+            bulk: Cache::new(max_age),
+            missing: Cache::new(DEFAULT_MISSING_MAX_AGE).with_capacity(capacity),
+            rendered: RenderCache::new(capacity),
+            hits: Arc::new(AtomicUsize::new(0)),
+            misses: Arc::new(AtomicUsize::new(0)),
+            snapshot_path: None,
         }
     }
 
-    pub async fn get_or_fetch(&self, id: NoradId) -> Result<String, Box<dyn std::error::Error>> {
-        let cache_entry = self
+    /// Answers IDs Space-Track had no GP data for from the cache for `max_age` before asking again
+    pub fn with_missing_max_age(mut self, max_age: Duration) -> Self {
+        // Missing IDs are never answered from stale entries, so there is no point in keeping them
+        self.missing = self.missing.with_max_age(max_age).with_max_stale(max_age);
+        self
+    }
+
+    /// Refreshes GP data sooner or later depending on the object's orbit, looked up in the
+    /// catalog
+    ///
+    /// Without it, GP data of all objects shares the same bounds.
+    pub fn with_catalog(mut self, catalog: SatelliteDatabase) -> Self {
+        // Scaled to how quickly the elements go out of date
+        self.entries = self
             .entries
-            .lock()
-            .expect("cache mutex poisoned")
-            .get(&id)
-            .cloned();
-
-        match cache_entry {
-            Some((fetch_time, data)) if fetch_time.elapsed() < MAX_AGE => Ok(data.clone()),
-            _ => {
-                let data = self.fetch(&id).await?;
-
-                self.entries
-                    .lock()
-                    .expect("cache mutex poisoned")
-                    .insert(id, (Instant::now(), data.clone()));
-
-                Ok(data.clone())
+            .with_freshness(move |id, freshness| match catalog.regime(id) {
+                // Drag makes low orbits drift away from their elements within hours
+                Some(Regime::Leo) => freshness.scaled(0.5),
+                Some(Regime::Geo | Regime::Heo) => freshness.scaled(3.0),
+                Some(Regime::Meo) | None => freshness,
+            });
+        self
+    }
+
+    /// GP data for the ID, refetched if the cached data is older than `max_age` when given
+    pub async fn get_or_fetch(
+        &self,
+        id: NoradId,
+        max_age: Option<Duration>,
+    ) -> Result<CachedData<GpRecord>, Error> {
+        if self.is_missing(id) {
+            return Err(no_gp_data(id));
+        }
+
+        let freshness = self.freshness(id, max_age);
+
+        // Owned so an expired entry can be refreshed in the background
+        let this = self.clone();
+        let result = self
+            .entries
+            .get_or_fetch_within(id, freshness, move || async move {
+                let result = this.fetch(&id).await;
+
+                // Noted here so failed background refreshes count as well
+                if let Err(Error::NotFound(_)) = &result {
+                    this.missing.put(id, ());
+                }
+
+                result
+            })
+            .await;
+
+        match &result {
+            Ok(cached) if cached.hit => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                metrics::counter!("gpcache_cache_hits_total").increment(1);
+            }
+            Ok(_) => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                metrics::counter!("gpcache_cache_misses_total").increment(1);
+                debug!(norad_id = id, "GP cache miss");
             }
+            Err(_) => {}
+        }
+
+        result
+    }
+
+    /// Bounds for lookups of the object's GP data, the same for every format so the responses
+    /// agree on how old the data may be
+    fn freshness(&self, id: NoradId, max_age: Option<Duration>) -> Freshness {
+        let freshness = self.entries.freshness(id);
+
+        match max_age {
+            Some(max_age) => freshness.within(max_age),
+            None => freshness,
+        }
+    }
+
+    /// Up to `limit` cached GP entries with IDs above `after` in ascending order, without
+    /// counting as an access, along with the ID to continue after if there are more
+    pub fn cached_entries(
+        &self,
+        after: Option<NoradId>,
+        limit: usize,
+    ) -> (Vec<Snapshot<NoradId, GpRecord>>, Option<NoradId>) {
+        self.entries.snapshot_after(after, limit)
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            entries: self.entries.len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
         }
     }
 
-    pub async fn fetch(&self, id: &NoradId) -> Result<String, reqwest::Error> {
-        self.client
-            .query(format!("{QUERY_URL}/{id}"))
-            .await?
-            .text()
+    /// Whether Space-Track had no GP data for the ID when it was last asked
+    fn is_missing(&self, id: NoradId) -> bool {
+        self.missing.get(id).is_some_and(|cached| !cached.stale)
+    }
+
+    /// GP data for many objects at once, fetching all that aren't cached in a single query
+    ///
+    /// Objects without any data are left out, so a failing upstream only costs the misses.
+    pub async fn get_or_fetch_many(&self, ids: &[NoradId]) -> HashMap<NoradId, Arc<GpRecord>> {
+        self.get_or_fetch_with(ids, |misses| {
+            misses
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(",")
+        })
+        .await
+    }
+
+    /// GP data for IDs from a block of consecutive ones, fetching everything that isn't cached in
+    /// a single range query
+    pub async fn get_or_fetch_range(&self, ids: &[NoradId]) -> HashMap<NoradId, Arc<GpRecord>> {
+        // Cached objects in between the misses are fetched again, which is cheaper than a long list
+        self.get_or_fetch_with(ids, |misses| {
+            format!("{}--{}", misses[0], misses[misses.len() - 1])
+        })
+        .await
+    }
+
+    /// Cached GP data for the IDs, with `query` turning the sorted IDs that missed into the ID
+    /// part of the upstream query
+    async fn get_or_fetch_with(
+        &self,
+        ids: &[NoradId],
+        query: impl FnOnce(&[NoradId]) -> String,
+    ) -> HashMap<NoradId, Arc<GpRecord>> {
+        let mut records = HashMap::new();
+        let mut stale = HashMap::new();
+
+        for &id in ids {
+            match self.entries.get(id) {
+                Some(cached) if !cached.stale => {
+                    records.insert(id, cached.data);
+                }
+                Some(cached) => {
+                    stale.insert(id, cached.data);
+                }
+                None => {}
+            }
+        }
+
+        let mut misses = ids
+            .iter()
+            .copied()
+            .filter(|&id| !records.contains_key(&id) && !self.is_missing(id))
+            .collect::<Vec<_>>();
+        misses.sort_unstable();
+
+        if !misses.is_empty() {
+            match self.fetch_many(&query(&misses)).await {
+                Ok(fetched) => {
+                    for record in fetched {
+                        let id = record.norad_cat_id;
+                        records.insert(id, self.entries.put(id, record));
+                    }
+
+                    for &id in &misses {
+                        if !records.contains_key(&id) && !stale.contains_key(&id) {
+                            self.missing.put(id, ());
+                        }
+                    }
+                }
+                Err(e) => warn!(count = misses.len(), error = %e, "Failed to fetch GP data"),
+            }
+        }
+
+        // Slightly outdated data beats no data at all
+        for (id, data) in stale {
+            records.entry(id).or_insert(data);
+        }
+
+        records
+    }
+
+    /// Renders the GP data, reusing the output until the entry is refreshed
+    ///
+    /// Returns `None` for formats that have to be fetched with [`Self::get_or_fetch_formatted`].
+    pub fn render(&self, cached: &CachedData<GpRecord>, format: Format) -> Option<Output> {
+        self.rendered.get_or_render(&cached.data, format)
+    }
+
+    /// GP data for the object exactly as Space-Track returns it in the given format, refetched
+    /// if the cached data is older than `max_age` when given
+    pub async fn get_or_fetch_formatted(
+        &self,
+        id: NoradId,
+        format: Format,
+        max_age: Option<Duration>,
+    ) -> Result<CachedData<Output>, Error> {
+        let freshness = self.freshness(id, max_age);
+
+        let this = self.clone();
+        self.formatted
+            .get_or_fetch_within(Formatted { id, format }, freshness, move || async move {
+                let body = this.fetch_formatted(&id, format).await?;
+                Ok(Output::new(Bytes::from(body)))
+            })
+            .await
+    }
+
+    pub async fn get_or_fetch_tle_latest(&self, id: NoradId) -> Result<CachedData<String>, Error> {
+        let this = self.clone();
+        self.tle_latest
+            .get_or_fetch(id, move || async move { this.fetch_tle_latest(&id).await })
             .await
     }
+
+    /// GP data for all active payloads in low earth orbit
+    pub async fn get_or_fetch_leo_payloads(&self) -> Result<CachedData<Vec<GpRecord>>, Error> {
+        let client = self.client.clone();
+        self.bulk
+            .get_or_fetch("leo_payloads", move || async move {
+                client.query_json(LEO_PAYLOADS_PATH.into()).await
+            })
+            .await
+    }
+
+    /// Fetches GP data for objects that are about to be requested, `batch_size` at a time
+    pub async fn warm_up(self, ids: Vec<NoradId>, batch_size: usize) {
+        let mut missing: Vec<NoradId> = Vec::new();
+
+        for batch in ids.chunks(batch_size) {
+            let records = self.get_or_fetch_many(batch).await;
+            missing.extend(batch.iter().filter(|id| !records.contains_key(id)));
+        }
+
+        if missing.is_empty() {
+            info!(count = ids.len(), "Warmed up the GP cache");
+        } else {
+            warn!(?missing, "Failed to warm up GP data for some objects");
+        }
+    }
+
+    /// Periodically refreshes the `top_k` most requested GP entries before they expire
+    pub async fn keep_warm(self, top_k: usize, margin: Duration) {
+        let mut interval = tokio::time::interval(PREWARM_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            // Refresh one at a time so warming never bursts against the upstream
+            for id in self.entries.expiring_hot_keys(top_k, margin) {
+                if let Err(e) = self.entries.refresh(id, || self.fetch(&id)).await {
+                    warn!(norad_id = id, error = %e, "Failed to prewarm GP data");
+                }
+            }
+        }
+    }
+
+    /// Loads the GP entries persisted to `path` and keeps flushing them there
+    ///
+    /// Entries keep the age and access count they had, so expiry and prewarming carry on
+    /// as if the process had never been restarted.
+    pub fn load_from(mut self, path: PathBuf) -> Self {
+        match std::fs::read(&path) {
+            Ok(bytes) => match serde_json::from_slice::<Vec<PersistedEntry<GpRecord>>>(&bytes) {
+                Ok(persisted) => {
+                    info!(count = persisted.len(), "Restoring cached GP entries");
+
+                    for entry in persisted {
+                        self.entries.restore(Snapshot {
+                            key: entry.record.norad_cat_id,
+                            fetched: entry.fetched,
+                            accesses: entry.accesses,
+                            data: Arc::new(entry.record),
+                        });
+                    }
+                }
+                Err(e) => {
+                    warn!(path = %path.display(), error = %e, "Ignoring malformed cache snapshot")
+                }
+            },
+            // Nothing has been persisted yet on the first start
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => warn!(path = %path.display(), error = %e, "Failed to read cache snapshot"),
+        }
+
+        self.snapshot_path = Some(path);
+        self
+    }
+
+    /// Writes the GP entries to the snapshot file, if there is one
+    pub fn flush(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(path) = &self.snapshot_path else {
+            return Ok(());
+        };
+
+        let snapshot = self.entries.snapshot();
+        let persisted = snapshot
+            .iter()
+            .map(|entry| PersistedEntry {
+                fetched: entry.fetched,
+                accesses: entry.accesses,
+                record: &*entry.data,
+            })
+            .collect::<Vec<_>>();
+
+        // Write next to the target and rename so a crash never leaves a truncated file behind
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, serde_json::to_vec(&persisted)?)?;
+        std::fs::rename(temporary, path)?;
+
+        Ok(())
+    }
+
+    /// Periodically flushes the GP entries to disk
+    pub async fn persist(self) {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+
+        // The first tick completes immediately and there is nothing new to save yet
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            // Serializing thousands of entries shouldn't hold up request handling
+            let this = self.clone();
+            let flushed =
+                tokio::task::spawn_blocking(move || this.flush().map_err(|e| e.to_string())).await;
+
+            match flushed {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!(error = %e, "Failed to flush cache snapshot"),
+                Err(e) => error!(error = %e, "Cache snapshot flush panicked"),
+            }
+        }
+    }
+
+    pub async fn fetch(&self, id: &NoradId) -> Result<GpRecord, Error> {
+        let records: Vec<GpRecord> = self.client.query_json(format!("{QUERY_PATH}/{id}")).await?;
+
+        records.into_iter().next().ok_or_else(|| no_gp_data(*id))
+    }
+
+    /// Fetches GP data for a comma separated list or a `start--end` range of NORAD IDs
+    async fn fetch_many(&self, ids: &str) -> Result<Vec<GpRecord>, Error> {
+        self.client.query_json(format!("{QUERY_PATH}/{ids}")).await
+    }
+
+    pub async fn fetch_formatted(&self, id: &NoradId, format: Format) -> Result<String, Error> {
+        let body = self
+            .client
+            .query_text(format!("{QUERY_PATH}/{id}/format/{format}"))
+            .await?;
+
+        if body.trim().is_empty() {
+            return Err(no_gp_data(*id));
+        }
+
+        Ok(body)
+    }
+
+    pub async fn fetch_tle_latest(&self, id: &NoradId) -> Result<String, Error> {
+        let tle = self
+            .client
+            .query_text(format!("{TLE_LATEST_PATH}/{id}/format/tle"))
+            .await?;
+
+        if tle.trim().is_empty() {
+            return Err(Error::NotFound(format!("no TLE for NORAD ID {id}")));
+        }
+
+        Ok(tle)
+    }
+}
+
+fn no_gp_data(id: NoradId) -> Error {
+    Error::NotFound(format!("no GP data for NORAD ID {id}"))
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Xml => "application/xml",
+            Format::Tle | Format::ThreeLine => "text/plain",
+            Format::Csv => "text/csv",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "json" => Ok(Format::Json),
+            "xml" => Ok(Format::Xml),
+            "tle" => Ok(Format::Tle),
+            "3le" => Ok(Format::ThreeLine),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("unknown format '{s}'")),
+        }
+    }
+}
+
+// Matches the names Space-Track uses in `format/...`
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Format::Json => "json",
+            Format::Xml => "xml",
+            Format::Tle => "tle",
+            Format::ThreeLine => "3le",
+            Format::Csv => "csv",
+        };
+
+        f.write_str(name)
+    }
+}
+
+impl<'de> Deserialize<'de> for Format {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl fmt::Display for Formatted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.id, self.format)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{self, MockClient};
+    use futures_util::future::join_all;
+
+    const ISS: &str = include_str!("fixtures/iss_gp.json");
+    const ISS_PATH: &str = "/basicspacedata/query/class/gp/NORAD_CAT_ID/25544";
+    const SATCAT: &str = include_str!("fixtures/satcat.json");
+    const SATCAT_PATH: &str =
+        "/basicspacedata/query/class/satcat/orderby/NORAD_CAT_ID%20asc/emptyresult/show";
+    const CAPACITY: NonZeroUsize = match NonZeroUsize::new(100) {
+        Some(capacity) => capacity,
+        None => unreachable!(),
+    };
+
+    fn cache(
+        client: &Arc<MockClient>,
+        max_age: Duration,
+        max_stale: Duration,
+    ) -> PerturbationCache {
+        PerturbationCache::new(client.clone(), max_age, max_stale, CAPACITY)
+    }
+
+    #[tokio::test]
+    async fn entries_are_fetched_again_once_expired() {
+        let client = Arc::new(MockClient::new().respond(ISS_PATH, ISS));
+        let max_age = Duration::from_millis(100);
+        let cache = cache(&client, max_age, max_age);
+
+        assert!(!cache.get_or_fetch(25544, None).await.unwrap().hit);
+        assert!(cache.get_or_fetch(25544, None).await.unwrap().hit);
+        assert_eq!(client.queries(ISS_PATH), 1);
+
+        tokio::time::sleep(max_age).await;
+        assert!(!cache.get_or_fetch(25544, None).await.unwrap().hit);
+        assert_eq!(client.queries(ISS_PATH), 2);
+    }
+
+    #[tokio::test]
+    async fn stale_entries_are_served_while_refreshing() {
+        let client = Arc::new(MockClient::new().respond(ISS_PATH, ISS));
+        let max_age = Duration::from_millis(100);
+        let cache = cache(&client, max_age, Duration::from_secs(60));
+
+        cache.get_or_fetch(25544, None).await.unwrap();
+        tokio::time::sleep(max_age).await;
+
+        let cached = cache.get_or_fetch(25544, None).await.unwrap();
+        assert!(cached.hit && cached.stale);
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(client.queries(ISS_PATH), 2);
+        assert!(!cache.get_or_fetch(25544, None).await.unwrap().stale);
+    }
+
+    #[tokio::test]
+    async fn expired_entries_are_served_when_upstream_times_out() {
+        let client = Arc::new(MockClient::new().respond(ISS_PATH, ISS));
+        let max_age = Duration::from_millis(100);
+        let cache = cache(&client, max_age, max_age);
+
+        cache.get_or_fetch(25544, None).await.unwrap();
+        tokio::time::sleep(max_age).await;
+        let timeout = mock::timeout().await;
+        assert!(matches!(timeout, Error::Timeout(_)));
+        client.answer(ISS_PATH, Err(timeout));
+
+        let cached = cache.get_or_fetch(25544, None).await.unwrap();
+        assert!(cached.hit && cached.stale);
+        assert_eq!(cached.data.norad_cat_id, 25544);
+        assert_eq!(client.queries(ISS_PATH), 2);
+    }
+
+    #[tokio::test]
+    async fn concurrent_misses_share_a_single_fetch() {
+        let client = Arc::new(
+            MockClient::new()
+                .respond(ISS_PATH, ISS)
+                .with_delay(Duration::from_millis(50)),
+        );
+        let cache = cache(&client, Duration::from_secs(60), Duration::from_secs(60));
+
+        let lookups = (0..10).map(|_| cache.get_or_fetch(25544, None));
+        for result in join_all(lookups).await {
+            assert_eq!(result.unwrap().data.norad_cat_id, 25544);
+        }
+
+        assert_eq!(client.queries(ISS_PATH), 1);
+    }
+
+    #[tokio::test]
+    async fn batches_keep_geostationary_data_for_longer() {
+        let goes = ISS.replace("25544", "41866");
+        let path = "/basicspacedata/query/class/gp/NORAD_CAT_ID/41866";
+        let client = Arc::new(
+            MockClient::new()
+                .respond(path, goes)
+                .respond(SATCAT_PATH, SATCAT),
+        );
+
+        let catalog = SatelliteDatabase::new(client.clone(), Duration::from_secs(60));
+        catalog.update().await.unwrap();
+
+        let max_age = Duration::from_millis(100);
+        let cache = cache(&client, max_age, max_age).with_catalog(catalog);
+
+        assert!(cache.get_or_fetch_many(&[41866]).await.contains_key(&41866));
+        tokio::time::sleep(max_age).await;
+        assert!(cache.get_or_fetch_many(&[41866]).await.contains_key(&41866));
+        assert_eq!(client.queries(path), 1);
+    }
+
+    #[tokio::test]
+    async fn missing_objects_are_not_asked_for_again() {
+        let path = "/basicspacedata/query/class/gp/NORAD_CAT_ID/99999";
+        let client = Arc::new(MockClient::new().respond(path, "[]"));
+        let cache = cache(&client, Duration::from_secs(60), Duration::from_secs(60));
+
+        for _ in 0..3 {
+            let result = cache.get_or_fetch(99999, None).await;
+            assert!(matches!(result, Err(Error::NotFound(_))));
+        }
+
+        assert_eq!(client.queries(path), 1);
+    }
+
+    #[tokio::test]
+    async fn missing_objects_are_asked_for_again_after_their_max_age() {
+        let path = "/basicspacedata/query/class/gp/NORAD_CAT_ID/99999";
+        let client = Arc::new(MockClient::new().respond(path, "[]"));
+        let max_age = Duration::from_millis(50);
+        let cache = cache(&client, Duration::from_secs(60), Duration::from_secs(60))
+            .with_missing_max_age(max_age);
+
+        assert!(cache.get_or_fetch(99999, None).await.is_err());
+        assert!(cache.get_or_fetch(99999, None).await.is_err());
+        assert_eq!(client.queries(path), 1);
+
+        tokio::time::sleep(max_age).await;
+        assert!(cache.get_or_fetch(99999, None).await.is_err());
+        assert_eq!(client.queries(path), 2);
+    }
 }
@@ -0,0 +1,188 @@
+use crate::tle;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sgp4::{Constants, Elements};
+
+// WGS84 ellipsoid
+const EARTH_RADIUS: f64 = 6378.137;
+const EARTH_FLATTENING: f64 = 1.0 / 298.257223563;
+
+#[derive(Serialize, Debug)]
+pub struct State {
+    time: DateTime<Utc>,
+    /// TEME position in km
+    position: [f64; 3],
+    /// TEME velocity in km/s
+    velocity: [f64; 3],
+}
+
+/// Point on the ellipsoid below a satellite along with its height above it
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct Geodetic {
+    /// Degrees north
+    pub lat: f64,
+    /// Degrees east
+    pub lon: f64,
+    /// km above the ellipsoid
+    pub alt: f64,
+}
+
+impl State {
+    /// Position in the earth-fixed frame, ignoring polar motion
+    fn ecef(&self) -> [f64; 3] {
+        let gmst = sgp4::iau_epoch_to_sidereal_time(sgp4::julian_years_since_j2000(
+            &self.time.naive_utc(),
+        ));
+        let [x, y, z] = self.position;
+
+        [
+            gmst.cos() * x + gmst.sin() * y,
+            -gmst.sin() * x + gmst.cos() * y,
+            z,
+        ]
+    }
+
+    pub fn geodetic(&self) -> Geodetic {
+        let [x, y, z] = self.ecef();
+        let e2 = EARTH_FLATTENING * (2.0 - EARTH_FLATTENING);
+        let p = (x * x + y * y).sqrt();
+
+        // Converges to well below a metre within a few iterations for anything in orbit
+        let mut lat = z.atan2(p * (1.0 - e2));
+        let mut alt = 0.0;
+        for _ in 0..5 {
+            let n = EARTH_RADIUS / (1.0 - e2 * lat.sin().powi(2)).sqrt();
+            alt = p / lat.cos() - n;
+            lat = z.atan2(p * (1.0 - e2 * n / (n + alt)));
+        }
+
+        Geodetic {
+            lat: lat.to_degrees(),
+            lon: y.atan2(x).to_degrees(),
+            alt,
+        }
+    }
+}
+
+/// Parses a two- or three-line element set, verifying the line checksums
+pub fn parse_tle(input: &str) -> Result<Elements, Box<dyn std::error::Error>> {
+    let lines = input
+        .lines()
+        .map(str::trim_end)
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<_>>();
+
+    let (name, line1, line2) = match lines.as_slice() {
+        [line1, line2] => (None, *line1, *line2),
+        // 3LE files from Space-Track prefix the name line with a zero
+        [name, line1, line2] => (
+            Some(name.strip_prefix("0 ").unwrap_or(name).trim().to_owned()),
+            *line1,
+            *line2,
+        ),
+        _ => return Err(format!("expected 2 or 3 TLE lines, got {}", lines.len()).into()),
+    };
+
+    // The parser checks these as well, but can't tell the client what it expected
+    for (number, line) in [(1, line1), (2, line2)] {
+        if let Err(expected) = tle::verify_checksum(line) {
+            return Err(format!("bad checksum on line {number}, expected {expected}").into());
+        }
+    }
+
+    let elements = Elements::from_tle(name, line1.as_bytes(), line2.as_bytes())?;
+
+    Ok(elements)
+}
+
+pub fn propagate(
+    elements: &Elements,
+    time: DateTime<Utc>,
+) -> Result<State, Box<dyn std::error::Error>> {
+    let constants = Constants::from_elements(elements)?;
+    let minutes = elements.datetime_to_minutes_since_epoch(&time.naive_utc())?;
+    let prediction = constants.propagate(minutes)?;
+
+    Ok(State {
+        time,
+        position: prediction.position,
+        velocity: prediction.velocity,
+    })
+}
+
+/// Geodetic location of a ground observer
+#[derive(Debug, Clone, Copy)]
+pub struct Observer {
+    /// Degrees north
+    pub lat: f64,
+    /// Degrees east
+    pub lon: f64,
+    /// Metres above the ellipsoid
+    pub alt: f64,
+}
+
+/// Direction and distance from an observer to a satellite
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct LookAngles {
+    /// Degrees clockwise from north
+    pub azimuth: f64,
+    /// Degrees above the horizon
+    pub elevation: f64,
+    /// km
+    pub range: f64,
+}
+
+impl Observer {
+    pub fn new(lat: f64, lon: f64, alt: f64) -> Result<Self, String> {
+        if !(-90.0..=90.0).contains(&lat) {
+            return Err(format!("latitude {lat} is outside of [-90, 90]"));
+        }
+
+        if !(-180.0..=180.0).contains(&lon) {
+            return Err(format!("longitude {lon} is outside of [-180, 180]"));
+        }
+
+        // Somewhere between the Dead Sea and the edge of the atmosphere
+        if !(-1000.0..=100_000.0).contains(&alt) {
+            return Err(format!("altitude {alt} m is outside of [-1000, 100000]"));
+        }
+
+        Ok(Self { lat, lon, alt })
+    }
+
+    fn ecef(&self) -> [f64; 3] {
+        let (lat, lon, alt) = (
+            self.lat.to_radians(),
+            self.lon.to_radians(),
+            self.alt / 1000.0,
+        );
+        let e2 = EARTH_FLATTENING * (2.0 - EARTH_FLATTENING);
+        let n = EARTH_RADIUS / (1.0 - e2 * lat.sin().powi(2)).sqrt();
+
+        [
+            (n + alt) * lat.cos() * lon.cos(),
+            (n + alt) * lat.cos() * lon.sin(),
+            (n * (1.0 - e2) + alt) * lat.sin(),
+        ]
+    }
+
+    pub fn look_angles(&self, state: &State) -> LookAngles {
+        let satellite = state.ecef();
+        let observer = self.ecef();
+        let [rx, ry, rz] = [0, 1, 2].map(|i| satellite[i] - observer[i]);
+
+        // Project onto the observer's south/east/zenith axes
+        let (lat, lon) = (self.lat.to_radians(), self.lon.to_radians());
+        let south = lat.sin() * lon.cos() * rx + lat.sin() * lon.sin() * ry - lat.cos() * rz;
+        let east = -lon.sin() * rx + lon.cos() * ry;
+        let zenith = lat.cos() * lon.cos() * rx + lat.cos() * lon.sin() * ry + lat.sin() * rz;
+
+        let range = (rx * rx + ry * ry + rz * rz).sqrt();
+
+        LookAngles {
+            azimuth: east.atan2(-south).to_degrees().rem_euclid(360.0),
+            elevation: (zenith / range).asin().to_degrees(),
+            range,
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use crate::{gp::GpRecord, locks::Recover, perturbation::Format, tle, NoradId};
+use bytes::Bytes;
+use lru::LruCache;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+};
+
+/// Renders the record in a format that can be derived from the GP data without a round-trip
+pub fn render(record: &GpRecord, format: Format) -> Option<String> {
+    match format {
+        Format::Json => Some(record.to_json()),
+        Format::Tle => {
+            let [line1, line2] = tle::render(record);
+            Some(format!("{line1}\n{line2}\n"))
+        }
+        Format::ThreeLine => Some(tle::render_3le(record)),
+        Format::Xml | Format::Csv => None,
+    }
+}
+
+/// Response body along with an entity tag that changes whenever the body does
+#[derive(Clone)]
+pub struct Output {
+    pub body: Bytes,
+    pub etag: String,
+}
+
+impl Output {
+    pub fn new(body: Bytes) -> Self {
+        let mut hasher = DefaultHasher::new();
+        body.hash(&mut hasher);
+        let etag = format!("\"{:016x}\"", hasher.finish());
+
+        Self { body, etag }
+    }
+}
+
+struct Rendered {
+    /// The GP entry this was rendered from, a refresh replaces it with a different allocation
+    source: Arc<GpRecord>,
+    output: Output,
+}
+
+/// Memoizes rendered GP data so hot objects aren't formatted again on every request
+///
+/// Outputs are kept as [`Bytes`] so serving them is just a reference count bump, and their
+/// entity tags are only computed once.
+#[derive(Clone)]
+pub struct RenderCache {
+    entries: Arc<Mutex<LruCache<(NoradId, Format), Rendered>>>,
+}
+
+impl RenderCache {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(LruCache::new(capacity))),
+        }
+    }
+
+    /// Returns `None` for formats that can't be rendered locally
+    pub fn get_or_render(&self, record: &Arc<GpRecord>, format: Format) -> Option<Output> {
+        let key = (record.norad_cat_id, format);
+
+        if let Some(rendered) = self
+            .entries
+            .lock_or_recover()
+            .get(&key)
+            .filter(|rendered| Arc::ptr_eq(&rendered.source, record))
+        {
+            return Some(rendered.output.clone());
+        }
+
+        let output = Output::new(Bytes::from(render(record, format)?));
+
+        self.entries.lock_or_recover().put(
+            key,
+            Rendered {
+                source: record.clone(),
+                output: output.clone(),
+            },
+        );
+
+        Some(output)
+    }
+}
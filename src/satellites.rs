@@ -1,17 +1,58 @@
-use crate::{api::SpaceTrackClient, NoradId};
-use serde::{Deserialize, Deserializer, Serialize};
+use crate::{
+    api::UpstreamClient,
+    de::{deserialize_number_from_string, deserialize_optional_number_from_string},
+    error::Error,
+    locks::RecoverRw,
+    NoradId,
+};
+use chrono::{Datelike, NaiveDate};
+use serde::{Deserialize, Serialize};
 use std::{
     cmp::Reverse,
     collections::HashMap,
-    fmt::Display,
     str::FromStr,
     sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
 };
 use sublime_fuzzy::best_match;
+use tracing::{error, info, warn};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+const MAX_RESULTS: usize = 20;
+// What a typeahead box is after, whether the query happens to be a prefix or not
+const SUGGESTED_TYPES: &[ObjectType] = &[ObjectType::Payload, ObjectType::RocketBody];
+// Highest catalog number the Alpha-5 scheme can express, Z9999
+const DEFAULT_MAX_ID: NoradId = 339_999;
+// Drag brings objects down within weeks once their perigee is this low, in km
+const DEFAULT_REENTRY_PERIGEE: f64 = 200.0;
+// Until the catalog has been loaded for the first time, there is nothing to serve searches from
+const INITIAL_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+// Differences that count as one unit of orbital distance
+const ALTITUDE_SCALE: f64 = 50.0; // km
+const INCLINATION_SCALE: f64 = 1.0; // degrees
 
-const QUERY_URL: &str = "https://www.space-track.org/basicspacedata/query/class/satcat/orderby/NORAD_CAT_ID%20asc/emptyresult/show";
+// Geosynchronous orbits take one sidereal day, in minutes
+const GEO_PERIOD: f64 = 1436.1;
+// Altitude of geostationary orbits in km
+const GEO_ALTITUDE: f64 = 35786.0;
+// Equatorial radius in km, altitudes are measured from it
+const EARTH_RADIUS: f64 = 6378.137;
+// Beyond this, where the orbit is along the way matters more than its altitude
+const HEO_MIN_ECCENTRICITY: f64 = 0.25;
+// Highest perigee in km that still counts as low earth orbit
+const LEO_MAX_PERIGEE: f64 = 2000.0;
+const QUERY_PATH: &str =
+    "/basicspacedata/query/class/satcat/orderby/NORAD_CAT_ID%20asc/emptyresult/show";
+const CHANGED_QUERY_PATH: &str = "/basicspacedata/query/class/satcat/FILE";
+const LATEST_FILE_QUERY_PATH: &str =
+    "/basicspacedata/query/class/satcat/orderby/FILE%20desc/limit/1/predicates/FILE/emptyresult/show";
+// Incremental updates never see objects that are dropped from the catalog entirely
+const DEFAULT_FULL_UPDATE_INTERVAL: Duration = Duration::from_secs(60 * 60 * 24 * 7);
 
+// Serialized names match the `FromStr` vocabulary used by query filters
 #[derive(Serialize, Debug, PartialEq, Eq, Clone)]
+#[serde(rename_all = "snake_case")]
 pub enum ObjectType {
     RocketBody,
     Payload,
@@ -19,17 +60,31 @@ pub enum ObjectType {
     Unknown,
 }
 
+/// Rough classification of an orbit by its altitude and shape
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Regime {
+    /// Perigee in low earth orbit
+    Leo,
+    /// Between low earth orbit and geosynchronous altitude
+    Meo,
+    /// Geostationary, or close to it, like the graveyard orbits just above
+    Geo,
+    /// Highly elliptical, like Molniya or transfer orbits
+    Heo,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub struct OrbitData {
-    #[serde(deserialize_with = "deserialize_number_from_string")]
-    period: f64,
-    #[serde(deserialize_with = "deserialize_number_from_string")]
-    inclination: f64,
-    #[serde(deserialize_with = "deserialize_number_from_string")]
-    apogee: f64,
-    #[serde(deserialize_with = "deserialize_number_from_string")]
-    perigee: f64,
+    #[serde(default, deserialize_with = "deserialize_optional_number_from_string")]
+    period: Option<f64>,
+    #[serde(default, deserialize_with = "deserialize_optional_number_from_string")]
+    inclination: Option<f64>,
+    #[serde(default, deserialize_with = "deserialize_optional_number_from_string")]
+    apogee: Option<f64>,
+    #[serde(default, deserialize_with = "deserialize_optional_number_from_string")]
+    perigee: Option<f64>,
 }
 
 // Unused fields are commented out but do exist if needed in the future
@@ -42,17 +97,38 @@ pub struct Satellite {
     )]
     id: NoradId,
 
-    // object_id: String,
+    /// International designator (COSPAR ID) like `1998-067A`
+    object_id: Option<String>,
     object_type: ObjectType,
     object_name: String,
+    /// Folded name for fuzzy matching, computed once when the catalog is ingested
+    #[serde(skip)]
+    search_name: String,
 
-    // country: String,
+    /// Space-Track's abbreviation for the owner, like `US` or `PRC`
+    #[serde(default)]
+    country: Option<String>,
     // site: String,
     launch: String, // 1957-10-04
+    /// Parsed `launch`, if it is a valid date
+    #[serde(skip)]
+    launch_date: Option<NaiveDate>,
     decay: Option<String>,
+    /// Parsed `decay`, if it is a valid date
+    #[serde(skip)]
+    decay_date: Option<NaiveDate>,
 
+    // Objects without a known orbit have some or all of these fields empty
     #[serde(flatten)]
-    orbit: Option<OrbitData>,
+    orbit: OrbitData,
+
+    /// Number of the upload that last changed this entry, increasing with every upload
+    #[serde(
+        default,
+        skip_serializing,
+        deserialize_with = "deserialize_optional_number_from_string"
+    )]
+    file: Option<u64>,
 }
 
 #[derive(Serialize, Debug)]
@@ -61,109 +137,866 @@ pub struct SearchResult {
     satellite: Satellite,
 }
 
+/// The matches of a search that were asked for
+#[derive(Debug)]
+pub struct SearchPage<T> {
+    pub results: Vec<T>,
+    /// Number of matches across all pages
+    pub total: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CollapsedResult {
+    #[serde(flatten)]
+    satellite: Satellite,
+    /// Number of matches sharing this base name, including this one
+    count: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Neighbor {
+    #[serde(flatten)]
+    satellite: Satellite,
+    /// Dimensionless distance in apogee, perigee and inclination, lower is closer
+    distance: f64,
+}
+
+pub struct SearchOptions<'a> {
+    pub allowed_types: &'a [ObjectType],
+    /// Rank non-decayed payloads above other matches
+    pub prefer_payloads: bool,
+    /// Search every object instead of just the active ones
+    pub full_catalog: bool,
+    /// Keep objects that have already re-entered
+    pub include_decayed: bool,
+    /// Only keep objects owned by this country, compared case-insensitively
+    pub country: Option<&'a str>,
+    /// Only keep objects with a known orbit in this regime
+    pub regime: Option<Regime>,
+    /// Range in km that both perigee and apogee have to lie in
+    pub altitude: Bounds,
+    /// Range of inclinations in degrees
+    pub inclination: Bounds,
+    /// Range of launch dates, excluding objects without a known one
+    pub launched: Bounds<NaiveDate>,
+    /// Only keep objects that are likely to re-enter soon, see [`SatelliteDatabase::reentry_soon`]
+    pub reentry_soon: bool,
+    /// Number of results to return after skipping the first `offset`
+    pub limit: usize,
+    pub offset: usize,
+}
+
+pub struct CatalogOptions<'a> {
+    /// Object types to keep, all of them if not given
+    pub allowed_types: Option<&'a [ObjectType]>,
+    /// Only keep objects that have or haven't re-entered yet
+    pub decayed: Option<bool>,
+    /// Only keep objects launched in this year, excluding those without a known launch date
+    pub launch_year: Option<i32>,
+    /// Only keep objects that re-entered in this year
+    pub decay_year: Option<i32>,
+    /// Number of satellites to return after skipping the first `offset`
+    pub limit: usize,
+    pub offset: usize,
+}
+
+/// Optional lower and upper limit, both inclusive
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Bounds<T = f64> {
+    pub min: Option<T>,
+    pub max: Option<T>,
+}
+
+impl<T: PartialOrd + Copy> Bounds<T> {
+    fn is_set(&self) -> bool {
+        self.min.is_some() || self.max.is_some()
+    }
+
+    /// Whether all of `low..=high` lies within the bounds
+    fn contains(&self, low: T, high: T) -> bool {
+        self.min.is_none_or(|min| low >= min) && self.max.is_none_or(|max| high <= max)
+    }
+}
+
 #[derive(Clone)]
 pub struct SatelliteDatabase {
-    client: Arc<SpaceTrackClient>,
+    client: Arc<dyn UpstreamClient>,
     entries: Arc<RwLock<HashMap<NoradId, Satellite>>>,
+    /// Non-decayed objects other than debris, a small fraction of the catalog that most searches target
+    active: Arc<RwLock<HashMap<NoradId, Satellite>>>,
+    /// Uppercased international designators to NORAD IDs
+    designators: Arc<RwLock<HashMap<String, NoradId>>>,
+    last_updated: Arc<RwLock<Option<SystemTime>>>,
+    /// Time of the last update that downloaded the whole catalog instead of just the changes
+    last_full_update: Arc<RwLock<Option<SystemTime>>>,
+    /// Highest upload number among the entries, everything after it is new to us
+    last_file: Arc<RwLock<Option<u64>>>,
+    /// Age after which the catalog is considered outdated
+    max_age: Duration,
+    full_update_interval: Duration,
+    /// IDs above this are rejected without looking any further
+    max_id: NoradId,
+    /// Perigee in km below which objects are flagged as re-entering soon
+    reentry_perigee: f64,
+    updating: Arc<tokio::sync::Mutex<()>>,
 }
 
 impl SatelliteDatabase {
-    pub fn new(client: Arc<SpaceTrackClient>) -> Self {
+    pub fn new(client: Arc<dyn UpstreamClient>, max_age: Duration) -> Self {
         Self {
             client,
             entries: Arc::new(RwLock::new(HashMap::new())),
+            active: Arc::new(RwLock::new(HashMap::new())),
+            designators: Arc::new(RwLock::new(HashMap::new())),
+            last_updated: Arc::new(RwLock::new(None)),
+            last_full_update: Arc::new(RwLock::new(None)),
+            last_file: Arc::new(RwLock::new(None)),
+            max_age,
+            full_update_interval: DEFAULT_FULL_UPDATE_INTERVAL,
+            max_id: DEFAULT_MAX_ID,
+            reentry_perigee: DEFAULT_REENTRY_PERIGEE,
+            updating: Arc::new(tokio::sync::Mutex::new(())),
         }
     }
 
-    pub async fn update(&self) -> Result<(), Box<dyn std::error::Error>> {
-        println!("Updating satellite database ...");
+    /// Downloads the whole catalog this often and only the changes in between, zero disables
+    /// incremental updates
+    pub fn with_full_update_interval(mut self, interval: Duration) -> Self {
+        self.full_update_interval = interval;
+        self
+    }
 
-        let satellites = self.fetch().await?;
+    pub fn with_max_id(mut self, max_id: NoradId) -> Self {
+        self.max_id = max_id;
+        self
+    }
 
-        println!("Ingesting satellite list ...");
+    pub fn with_reentry_perigee(mut self, perigee: f64) -> Self {
+        self.reentry_perigee = perigee;
+        self
+    }
+
+    /// Whether the object is still up but low enough for drag to bring it down soon
+    ///
+    /// Only looks at the current perigee, not at how it has been changing.
+    pub fn reentry_soon(&self, satellite: &Satellite) -> bool {
+        satellite.decay.is_none()
+            && satellite
+                .orbit
+                .perigee
+                .is_some_and(|perigee| perigee < self.reentry_perigee)
+    }
 
-        let mut entries = self.entries.write().expect("satellite mutex poisoned");
-        *entries = satellites.into_iter().map(|s| (s.id.clone(), s)).collect();
+    /// Rejects IDs that can't have any GP data before they cost a query to Space-Track
+    ///
+    /// Until the catalog has been loaded, only the upper limit is checked.
+    pub fn check_id(&self, id: NoradId) -> Result<(), Error> {
+        if id > self.max_id {
+            return Err(Error::BadRequest(format!(
+                "NORAD ID {id} is above the highest valid one, {}",
+                self.max_id
+            )));
+        }
 
-        println!("Updated satellite database with {} entries", entries.len());
+        let catalogued = self.entries.read_or_recover().contains_key(&id);
+        if !catalogued && self.last_updated().is_some() {
+            return Err(Error::NotFound(format!(
+                "no catalog entry for NORAD ID {id}"
+            )));
+        }
 
         Ok(())
     }
 
-    pub fn search(&self, query: &str, allowed_types: &[ObjectType]) -> Vec<Satellite> {
-        // Protect our CPU :3
+    /// Time of the last successful update
+    pub fn last_updated(&self) -> Option<SystemTime> {
+        *self.last_updated.read_or_recover()
+    }
+
+    /// Number of objects in the catalog
+    pub fn len(&self) -> usize {
+        self.entries.read_or_recover().len()
+    }
+
+    /// Whether the catalog has never been loaded or updates have been failing for too long
+    pub fn is_outdated(&self) -> bool {
+        self.last_updated()
+            .and_then(|updated| updated.elapsed().ok())
+            .is_none_or(|age| age > self.max_age)
+    }
+
+    /// Fetches the entries that changed since the last update, or the whole catalog if it is
+    /// due for a full update
+    ///
+    /// Concurrent calls wait for the update that is already underway instead of starting their own.
+    pub async fn update(&self) -> Result<(), Error> {
+        let requested = SystemTime::now();
+        let _updating = self.updating.lock().await;
+
+        if self
+            .last_updated()
+            .is_some_and(|updated| updated >= requested)
+        {
+            return Ok(());
+        }
+
+        let full_update_due = self
+            .last_full_update
+            .read_or_recover()
+            .and_then(|updated| updated.elapsed().ok())
+            .is_none_or(|age| age >= self.full_update_interval);
+
+        let last_file = *self.last_file.read_or_recover();
+
+        // Nothing to rebuild the catalog from if there hasn't been an upload since the last one
+        if full_update_due && last_file.is_some() && self.is_unchanged_since(last_file).await {
+            info!(
+                file = last_file,
+                "Skipping full satellite database update, the catalog is unchanged"
+            );
+
+            let now = SystemTime::now();
+            *self.last_full_update.write_or_recover() = Some(now);
+            *self.last_updated.write_or_recover() = Some(now);
+            return Ok(());
+        }
+
+        let since = last_file.filter(|_| !full_update_due);
+
+        info!(since_file = since, "Updating satellite database");
+
+        let (satellites, skipped) = self.fetch(since).await?;
+
+        info!(count = satellites.len(), "Ingesting satellite list");
+
+        let mut entries = self.entries.write_or_recover();
+        let mut active = self.active.write_or_recover();
+        let mut designators = self.designators.write_or_recover();
+
+        // All maps are updated while holding the write locks so they stay consistent
+        if since.is_some() {
+            let changed = satellites.len();
+
+            for satellite in satellites {
+                // Decayed objects are still part of the changes, they just drop out of the index
+                if satellite.is_active() {
+                    active.insert(satellite.id, satellite.clone());
+                } else {
+                    active.remove(&satellite.id);
+                }
+
+                if let Some(designator) = &satellite.object_id {
+                    designators.insert(designator.to_ascii_uppercase(), satellite.id);
+                }
+
+                entries.insert(satellite.id, satellite);
+            }
+
+            info!(changed, "Merged satellite database changes");
+        } else {
+            *entries = satellites.into_iter().map(|s| (s.id, s)).collect();
+
+            *active = entries
+                .values()
+                .filter(|s| s.is_active())
+                .map(|s| (s.id, s.clone()))
+                .collect();
+
+            *designators = entries
+                .values()
+                .filter_map(|s| Some((s.object_id.as_deref()?.to_ascii_uppercase(), s.id)))
+                .collect();
+
+            *self.last_full_update.write_or_recover() = Some(SystemTime::now());
+        }
+
+        *self.last_file.write_or_recover() = entries.values().filter_map(|s| s.file).max();
+        *self.last_updated.write_or_recover() = Some(SystemTime::now());
+        metrics::gauge!("gpcache_satellite_db_entries").set(entries.len() as f64);
+
+        info!(
+            entries = entries.len(),
+            skipped, "Updated satellite database"
+        );
+
+        Ok(())
+    }
+
+    /// Loads the catalog and updates it every `interval`, keeping the current one if an update
+    /// fails
+    pub async fn keep_updated(self, interval: Duration) {
+        loop {
+            let next_update = match self.update().await {
+                Ok(()) => interval,
+                Err(e) if self.last_updated().is_none() => {
+                    error!(error = %e, "Failed to load satellite database");
+                    INITIAL_RETRY_INTERVAL
+                }
+                Err(e) => {
+                    warn!(error = %e, "Failed to update satellite database");
+                    interval
+                }
+            };
+
+            tokio::time::sleep(next_update).await;
+        }
+    }
+
+    /// The best matches for the query along with their scores
+    pub fn search(&self, query: &str, options: &SearchOptions) -> SearchPage<SearchResult> {
+        if let Some(exact) = self.exact_match(query, options) {
+            return SearchPage {
+                results: vec![exact],
+                total: 1,
+            };
+        }
+
+        let matches = self.matches(query, options);
+        SearchPage {
+            total: matches.len(),
+            results: matches
+                .into_iter()
+                .skip(options.offset)
+                .take(options.limit)
+                .collect(),
+        }
+    }
+
+    /// Like [`SatelliteDatabase::search`] but only keeps the best match for each base name
+    pub fn search_collapsed(
+        &self,
+        query: &str,
+        options: &SearchOptions,
+    ) -> SearchPage<CollapsedResult> {
+        if let Some(exact) = self.exact_match(query, options) {
+            return SearchPage {
+                results: vec![CollapsedResult {
+                    satellite: exact.satellite,
+                    count: 1,
+                }],
+                total: 1,
+            };
+        }
+
+        let mut groups: Vec<CollapsedResult> = Vec::new();
+        let mut group_indices: HashMap<String, usize> = HashMap::new();
+
+        // Matches are ranked, so the first one we see for every base name is its best
+        for r in self.matches(query, options) {
+            let base = base_name(&r.satellite.object_name).to_owned();
+
+            match group_indices.get(&base) {
+                Some(&index) => groups[index].count += 1,
+                None => {
+                    group_indices.insert(base, groups.len());
+                    groups.push(CollapsedResult {
+                        satellite: r.satellite,
+                        count: 1,
+                    });
+                }
+            }
+        }
+
+        SearchPage {
+            total: groups.len(),
+            results: groups
+                .into_iter()
+                .skip(options.offset)
+                .take(options.limit)
+                .collect(),
+        }
+    }
+
+    /// Active objects for a typeahead box, those whose name starts with the query first
+    ///
+    /// Prefix matches are a cheap scan and ranked newest first, the fuzzy search only runs if
+    /// there aren't enough of them.
+    pub fn suggest(&self, query: &str) -> Vec<Satellite> {
         if query.len() < 3 {
             return Vec::new();
         }
 
-        let entries = self.entries.read().expect("satellite mutex poisoned");
+        let prefix = fold(query);
+        let mut suggestions = {
+            let active = self.active.read_or_recover();
+            let mut prefixed = active
+                .values()
+                .filter(|s| SUGGESTED_TYPES.contains(&s.object_type))
+                .filter(|s| s.search_name.starts_with(&prefix))
+                .collect::<Vec<_>>();
+
+            // Catalog numbers are handed out in order, so higher ones are more recent launches
+            prefixed.sort_unstable_by_key(|s| Reverse(s.id));
+            prefixed
+                .into_iter()
+                .take(MAX_RESULTS)
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+
+        if suggestions.len() < MAX_RESULTS {
+            let options = SearchOptions {
+                allowed_types: SUGGESTED_TYPES,
+                prefer_payloads: false,
+                full_catalog: false,
+                include_decayed: false,
+                country: None,
+                regime: None,
+                altitude: Bounds::default(),
+                inclination: Bounds::default(),
+                launched: Bounds::default(),
+                reentry_soon: false,
+                limit: MAX_RESULTS,
+                offset: 0,
+            };
+
+            let fuzzy = self
+                .matches(query, &options)
+                .into_iter()
+                .map(|r| r.satellite)
+                .filter(|s| suggestions.iter().all(|suggestion| suggestion.id != s.id))
+                .take(MAX_RESULTS - suggestions.len())
+                .collect::<Vec<_>>();
+            suggestions.extend(fuzzy);
+        }
+
+        suggestions
+    }
 
-        // Try to short-circuit if the query is likely to be an ID and we have a matching entry
-        if let Some(satellite) = query
+    /// The entry if the query is a NORAD ID or international designator, scored above anything
+    /// the fuzzy search could come up with
+    fn exact_match(&self, query: &str, options: &SearchOptions) -> Option<SearchResult> {
+        let id = query
             .parse::<usize>()
             .ok()
-            .map(|id| entries.get(&id))
-            .flatten()
-            .cloned()
-        {
-            return vec![satellite];
+            .or_else(|| self.find_by_designator(query))?;
+
+        let entries = self.entries.read_or_recover();
+        let satellite = entries
+            .get(&id)
+            .filter(|s| options.include_decayed || s.decay.is_none())?;
+
+        Some(SearchResult {
+            score: isize::MAX,
+            satellite: satellite.clone(),
+        })
+    }
+
+    /// All fuzzy matches for the query, ranked best first
+    fn matches(&self, query: &str, options: &SearchOptions) -> Vec<SearchResult> {
+        // Protect our CPU :3
+        if query.len() < 3 {
+            return Vec::new();
         }
 
-        // Fall back to fuzzy search
-        let mut matches = entries
+        let entries = self.entries.read_or_recover();
+        let active;
+        let candidates = if options.full_catalog {
+            &entries
+        } else {
+            active = self.active.read_or_recover();
+            &active
+        };
+
+        // Fall back to fuzzy search, requiring every term to match somewhere in the name
+        let query = fold(query);
+        let terms = query.split_whitespace().collect::<Vec<_>>();
+        let mut matches = candidates
             .values()
-            .filter(|s| allowed_types.contains(&s.object_type))
+            .filter(|s| options.allowed_types.contains(&s.object_type))
+            .filter(|s| options.include_decayed || s.decay.is_none())
+            .filter(|s| options.country.is_none_or(|country| s.is_owned_by(country)))
+            .filter(|s| {
+                options
+                    .regime
+                    .is_none_or(|regime| s.regime() == Some(regime))
+            })
+            .filter(|s| s.orbit.within(&options.altitude, &options.inclination))
+            .filter(|s| s.launched_within(&options.launched))
+            .filter(|s| !options.reentry_soon || self.reentry_soon(s))
             .filter_map(|s| {
-                if let Some(m) = best_match(query, &s.object_name) {
-                    let score = m.score();
+                let score = terms
+                    .iter()
+                    .map(|term| {
+                        best_match(term, &s.search_name)
+                            .map(|m| m.score())
+                            // Reject really bad results
+                            .filter(|score| *score >= 0)
+                    })
+                    .sum::<Option<isize>>()?;
+
+                Some(SearchResult {
+                    score,
+                    satellite: s.clone(),
+                })
+            })
+            .collect::<Vec<_>>();
 
-                    // Reject really bad results
-                    if score < 0 {
-                        return None;
-                    }
+        // Best score first, more recent sats at the top if the score is equal.
+        // IDs are unique, so there are no ties left for a stable sort to preserve.
+        matches.sort_unstable_by(|a, b| {
+            b.score
+                .cmp(&a.score)
+                .then_with(|| b.satellite.id.cmp(&a.satellite.id))
+        });
 
-                    Some(SearchResult {
-                        score,
-                        satellite: s.clone(),
-                    })
-                } else {
-                    None
-                }
+        // Stable sort again so that active payloads come first while keeping the order within
+        if options.prefer_payloads {
+            matches.sort_by_key(|r| {
+                let s = &r.satellite;
+                Reverse((s.object_type == ObjectType::Payload) as u8 + s.decay.is_none() as u8)
+            });
+        }
+
+        matches
+    }
+
+    /// Catalog entry for the NORAD ID
+    pub fn get(&self, id: NoradId) -> Option<Satellite> {
+        self.entries.read_or_recover().get(&id).cloned()
+    }
+
+    /// Orbit regime of the object, if the catalog knows enough about its orbit
+    pub fn regime(&self, id: NoradId) -> Option<Regime> {
+        self.entries.read_or_recover().get(&id)?.regime()
+    }
+
+    /// Resolves an international designator like `1998-067A` to a NORAD ID
+    pub fn find_by_designator(&self, designator: &str) -> Option<NoradId> {
+        self.designators
+            .read_or_recover()
+            .get(&designator.trim().to_ascii_uppercase())
+            .copied()
+    }
+
+    /// Objects in orbit whose apogee, perigee and inclination are closest to those of `id`
+    ///
+    /// Returns `None` if the object isn't known or lacks the orbital parameters to compare.
+    pub fn neighbors(&self, id: NoradId) -> Option<Vec<Neighbor>> {
+        let entries = self.entries.read_or_recover();
+        let target = entries.get(&id)?.orbit.shape()?;
+
+        let mut neighbors = entries
+            .values()
+            .filter(|s| s.id != id && s.decay.is_none())
+            .filter_map(|s| {
+                let [apogee, perigee, inclination] = s.orbit.shape()?;
+                let distance = (((apogee - target[0]) / ALTITUDE_SCALE).powi(2)
+                    + ((perigee - target[1]) / ALTITUDE_SCALE).powi(2)
+                    + ((inclination - target[2]) / INCLINATION_SCALE).powi(2))
+                .sqrt();
+
+                Some(Neighbor {
+                    satellite: s.clone(),
+                    distance,
+                })
             })
             .collect::<Vec<_>>();
 
-        // Sort by ID first and then stably by the score
-        // which puts more recent sats at the top if the score is equal.
-        matches.sort_unstable_by_key(|r| Reverse(r.satellite.id));
-        matches.sort_by_key(|r| Reverse(r.score));
+        neighbors.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        neighbors.truncate(MAX_RESULTS);
+
+        Some(neighbors)
+    }
 
-        // Only return the top-n results
-        matches.truncate(20);
+    /// NORAD IDs of the satellites matching the filters, in ascending order
+    ///
+    /// Only the IDs are collected so large dumps can be served page by page through [`Self::get_many`].
+    pub fn catalog(&self, options: &CatalogOptions) -> Vec<NoradId> {
+        let entries = self.entries.read_or_recover();
 
-        matches.into_iter().map(|r| r.satellite).collect()
+        let mut ids = entries
+            .values()
+            .filter(|s| {
+                options
+                    .allowed_types
+                    .is_none_or(|types| types.contains(&s.object_type))
+            })
+            .filter(|s| {
+                options
+                    .decayed
+                    .is_none_or(|decayed| s.decay.is_some() == decayed)
+            })
+            .filter(|s| {
+                options
+                    .launch_year
+                    .is_none_or(|year| s.launch_date.is_some_and(|date| date.year() == year))
+            })
+            .filter(|s| {
+                options
+                    .decay_year
+                    .is_none_or(|year| s.decay_date.is_some_and(|date| date.year() == year))
+            })
+            .map(|s| s.id)
+            .collect::<Vec<_>>();
+
+        ids.sort_unstable();
+        ids.into_iter()
+            .skip(options.offset)
+            .take(options.limit)
+            .collect()
     }
 
-    async fn fetch(&self) -> Result<Vec<Satellite>, reqwest::Error> {
-        self.client.query(QUERY_URL.into()).await?.json().await
+    /// Satellites for the given IDs in the same order, skipping any that left the catalog since
+    pub fn get_many(&self, ids: &[NoradId]) -> Vec<Satellite> {
+        let entries = self.entries.read_or_recover();
+        ids.iter()
+            .filter_map(|id| entries.get(id).cloned())
+            .collect()
+    }
+
+    /// Whether the most recent upload to the catalog is still `file`
+    ///
+    /// Only a shortcut, so failing to find out simply counts as a change.
+    async fn is_unchanged_since(&self, file: Option<u64>) -> bool {
+        match self.latest_file().await {
+            Ok(latest) => latest == file,
+            Err(e) => {
+                warn!(error = %e, "Failed to check for satellite catalog changes");
+                false
+            }
+        }
+    }
+
+    /// Number of the most recent upload to the catalog
+    async fn latest_file(&self) -> Result<Option<u64>, Error> {
+        #[derive(Deserialize)]
+        struct File {
+            #[serde(rename = "FILE", deserialize_with = "deserialize_number_from_string")]
+            file: u64,
+        }
+
+        let latest: Vec<File> = self
+            .client
+            .query_json(LATEST_FILE_QUERY_PATH.into())
+            .await?;
+        Ok(latest.first().map(|latest| latest.file))
+    }
+
+    /// The whole catalog, or only the entries from uploads after `since`, along with the number
+    /// of records that had to be skipped
+    ///
+    /// Records are deserialized as they come in, so the raw catalog is never held in memory.
+    async fn fetch(&self, since: Option<u64>) -> Result<(Vec<Satellite>, usize), Error> {
+        let path = match since {
+            Some(file) => format!("{CHANGED_QUERY_PATH}/%3E{file}/emptyresult/show"),
+            None => QUERY_PATH.into(),
+        };
+
+        let mut records = self.client.query_json_elements(path).await?;
+        let mut satellites = Vec::new();
+        let mut skipped = 0;
+
+        // Deserialize one by one so a single malformed record doesn't cost us the whole catalog
+        while let Some(record) = records.next().await? {
+            match serde_json::from_slice::<Satellite>(&record) {
+                Ok(mut satellite) => {
+                    satellite.search_name = fold(&satellite.object_name);
+                    satellite.launch_date = satellite.launch.parse().ok();
+                    satellite.decay_date = satellite.decay.as_deref().and_then(|d| d.parse().ok());
+                    satellites.push(satellite);
+                }
+                Err(e) => {
+                    let record = String::from_utf8_lossy(&record);
+                    warn!(error = %e, %record, "Skipping malformed satellite record");
+                    skipped += 1;
+                }
+            }
+        }
+
+        Ok((satellites, skipped))
     }
 }
 
-fn deserialize_number_from_string<'de, T, D>(deserializer: D) -> Result<T, D::Error>
-where
-    D: Deserializer<'de>,
-    T: FromStr + serde::Deserialize<'de>,
-    <T as FromStr>::Err: Display,
-{
-    #[derive(Deserialize)]
-    #[serde(untagged)]
-    enum StringOrInt<T> {
-        String(String),
-        Number(T),
+impl OrbitData {
+    /// Apogee, perigee and inclination if all of them are known
+    fn shape(&self) -> Option<[f64; 3]> {
+        Some([self.apogee?, self.perigee?, self.inclination?])
     }
 
-    match StringOrInt::<T>::deserialize(deserializer)? {
-        StringOrInt::String(s) => s.parse::<T>().map_err(serde::de::Error::custom),
-        StringOrInt::Number(i) => Ok(i),
+    /// Whether the orbit is known well enough to lie within the bounds that are set
+    fn within(&self, altitude: &Bounds, inclination: &Bounds) -> bool {
+        let altitude_matches = !altitude.is_set()
+            || matches!(
+                (self.perigee, self.apogee),
+                (Some(perigee), Some(apogee)) if altitude.contains(perigee, apogee)
+            );
+        let inclination_matches = !inclination.is_set()
+            || self
+                .inclination
+                .is_some_and(|value| inclination.contains(value, value));
+
+        altitude_matches && inclination_matches
+    }
+}
+
+impl Satellite {
+    fn launched_within(&self, bounds: &Bounds<NaiveDate>) -> bool {
+        if !bounds.is_set() {
+            return true;
+        }
+
+        self.launch_date
+            .is_some_and(|date| bounds.contains(date, date))
+    }
+
+    fn is_owned_by(&self, country: &str) -> bool {
+        self.country
+            .as_deref()
+            .is_some_and(|owner| owner.eq_ignore_ascii_case(country))
+    }
+
+    /// Orbital regime, if enough of the orbit is known to tell
+    pub fn regime(&self) -> Option<Regime> {
+        let OrbitData {
+            period,
+            inclination,
+            apogee,
+            perigee,
+        } = self.orbit;
+        let (apogee, perigee) = (apogee?, perigee?);
+        let eccentricity = (apogee - perigee) / (apogee + perigee + 2.0 * EARTH_RADIUS);
+
+        // Within a few percent of a sidereal day or the matching altitude, inclined
+        // geosynchronous orbits don't count
+        let semi_major_axis = (apogee + perigee) / 2.0 + EARTH_RADIUS;
+        let geosynchronous = period.is_some_and(|period| (period / GEO_PERIOD - 1.0).abs() < 0.03)
+            || (semi_major_axis / (GEO_ALTITUDE + EARTH_RADIUS) - 1.0).abs() < 0.02;
+
+        if eccentricity > HEO_MIN_ECCENTRICITY {
+            Some(Regime::Heo)
+        } else if geosynchronous && inclination.is_some_and(|inclination| inclination < 15.0) {
+            Some(Regime::Geo)
+        } else if perigee < LEO_MAX_PERIGEE {
+            Some(Regime::Leo)
+        } else {
+            Some(Regime::Meo)
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        // Unknown objects are mostly recent launches that haven't been identified yet
+        self.decay.is_none() && self.object_type != ObjectType::Debris
+    }
+}
+
+impl SearchResult {
+    pub fn id(&self) -> NoradId {
+        self.satellite.id
+    }
+
+    pub fn satellite(&self) -> &Satellite {
+        &self.satellite
+    }
+}
+
+impl CollapsedResult {
+    pub fn id(&self) -> NoradId {
+        self.satellite.id
+    }
+
+    pub fn satellite(&self) -> &Satellite {
+        &self.satellite
+    }
+}
+
+const CSV_HEADER: [&str; 8] = [
+    "norad_id",
+    "object_name",
+    "object_type",
+    "launch",
+    "period",
+    "inclination",
+    "apogee",
+    "perigee",
+];
+
+/// Row of [`to_csv`], in the order of [`CSV_HEADER`]
+#[derive(Serialize)]
+struct CsvRow<'a> {
+    norad_id: NoradId,
+    object_name: &'a str,
+    object_type: &'a ObjectType,
+    launch: &'a str,
+    period: Option<f64>,
+    inclination: Option<f64>,
+    apogee: Option<f64>,
+    perigee: Option<f64>,
+}
+
+/// Renders the satellites as CSV with a header row, unknown orbital elements are left empty
+pub fn to_csv<'a>(
+    satellites: impl IntoIterator<Item = &'a Satellite>,
+) -> Result<Vec<u8>, csv::Error> {
+    // The header is written by hand so it is there even without any rows
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(Vec::new());
+    writer.write_record(CSV_HEADER)?;
+
+    for satellite in satellites {
+        writer.serialize(CsvRow {
+            norad_id: satellite.id,
+            object_name: &satellite.object_name,
+            object_type: &satellite.object_type,
+            launch: &satellite.launch,
+            period: satellite.orbit.period,
+            inclination: satellite.orbit.inclination,
+            apogee: satellite.orbit.apogee,
+            perigee: satellite.orbit.perigee,
+        })?;
+    }
+
+    writer
+        .into_inner()
+        .map_err(|e| csv::Error::from(e.into_error()))
+}
+
+/// Lowercase name for the fuzzy matcher, so `Ørsted-1` and `orsted 1` look the same to it
+///
+/// Diacritics are dropped, punctuation becomes whitespace and runs of whitespace collapse into one.
+fn fold(name: &str) -> String {
+    let folded = name
+        .nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .map(|c| match c {
+            // Letters that don't decompose into a base letter and a mark
+            'ø' | 'Ø' => 'o',
+            'ł' | 'Ł' => 'l',
+            'đ' | 'Đ' => 'd',
+            c if c.is_alphanumeric() => c,
+            _ => ' ',
+        })
+        .collect::<String>()
+        .to_lowercase();
+
+    folded.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Strips trailing serial numbers so e.g. "STARLINK-1234" and "STARLINK-1235" group together
+fn base_name(name: &str) -> &str {
+    let base = name
+        .trim_end_matches(|c: char| c.is_ascii_digit())
+        .trim_end_matches(['-', ' ', '_']);
+
+    if base.is_empty() {
+        name
+    } else {
+        base
+    }
+}
+
+impl FromStr for ObjectType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "rocket_body" => Ok(ObjectType::RocketBody),
+            "payload" => Ok(ObjectType::Payload),
+            "debris" => Ok(ObjectType::Debris),
+            "unknown" => Ok(ObjectType::Unknown),
+            _ => Err(format!("unknown object type '{s}'")),
+        }
     }
 }
 
@@ -179,13 +1012,257 @@ impl<'de> Deserialize<'de> for ObjectType {
             "PAYLOAD" => Ok(ObjectType::Payload),
             "DEBRIS" => Ok(ObjectType::Debris),
             "UNKNOWN" => Ok(ObjectType::Unknown),
-            _ => {
-                eprintln!(
-                    "Encountered unknown object type '{}', mapping to Unknown",
-                    string
-                );
-                Ok(ObjectType::Unknown)
-            }
+            // Accept our own serialization too so the type round-trips
+            other => Ok(other.parse().unwrap_or_else(|_| {
+                warn!(object_type = %string, "Unknown object type, mapping to Unknown");
+                ObjectType::Unknown
+            })),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockClient;
+
+    const SATCAT: &str = include_str!("fixtures/satcat.json");
+    const ALL_TYPES: &[ObjectType] = &[
+        ObjectType::Payload,
+        ObjectType::RocketBody,
+        ObjectType::Debris,
+        ObjectType::Unknown,
+    ];
+
+    async fn database() -> SatelliteDatabase {
+        let client = MockClient::new().respond(QUERY_PATH, SATCAT);
+        let db = SatelliteDatabase::new(Arc::new(client), Duration::from_secs(60));
+        db.update().await.unwrap();
+        db
+    }
+
+    fn options() -> SearchOptions<'static> {
+        SearchOptions {
+            allowed_types: ALL_TYPES,
+            prefer_payloads: false,
+            full_catalog: false,
+            include_decayed: false,
+            country: None,
+            regime: None,
+            altitude: Bounds::default(),
+            inclination: Bounds::default(),
+            launched: Bounds::default(),
+            reentry_soon: false,
+            limit: 20,
+            offset: 0,
+        }
+    }
+
+    fn ids(db: &SatelliteDatabase, query: &str, options: &SearchOptions) -> Vec<NoradId> {
+        let mut ids = db
+            .search(query, options)
+            .results
+            .iter()
+            .map(SearchResult::id)
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids
+    }
+
+    #[tokio::test]
+    async fn active_catalog_leaves_out_debris_and_decayed_objects() {
+        let db = database().await;
+
+        assert_eq!(ids(&db, "starlink", &options()), [44713, 44714]);
+        assert_eq!(ids(&db, "object c", &options()), [48900]);
+        assert!(ids(&db, "cosmos", &options()).is_empty());
+
+        let full = SearchOptions {
+            full_catalog: true,
+            ..options()
+        };
+        assert_eq!(ids(&db, "cosmos", &full), [34454]);
+
+        let decayed = SearchOptions {
+            include_decayed: true,
+            ..full
+        };
+        assert_eq!(ids(&db, "starlink", &decayed), [44713, 44714, 44720]);
+    }
+
+    #[tokio::test]
+    async fn filters_by_type_and_owner() {
+        let db = database().await;
+
+        let payloads = SearchOptions {
+            allowed_types: &[ObjectType::Payload],
+            ..options()
+        };
+        assert!(ids(&db, "object c", &payloads).is_empty());
+
+        let american = SearchOptions {
+            country: Some("us"),
+            ..options()
+        };
+        assert_eq!(ids(&db, "starlink", &american), [44713, 44714]);
+
+        let chinese = SearchOptions {
+            country: Some("PRC"),
+            ..options()
+        };
+        assert!(ids(&db, "starlink", &chinese).is_empty());
+    }
+
+    #[tokio::test]
+    async fn filters_by_orbit() {
+        let db = database().await;
+
+        let geo = SearchOptions {
+            regime: Some(Regime::Geo),
+            ..options()
+        };
+        assert_eq!(ids(&db, "goes", &geo), [41866]);
+        assert!(ids(&db, "starlink", &geo).is_empty());
+
+        let low = SearchOptions {
+            altitude: Bounds {
+                min: Some(500.0),
+                max: Some(600.0),
+            },
+            ..options()
+        };
+        assert_eq!(ids(&db, "starlink", &low), [44713, 44714]);
+        assert!(ids(&db, "iss", &low).is_empty());
+
+        let polar = SearchOptions {
+            inclination: Bounds {
+                min: Some(90.0),
+                max: None,
+            },
+            ..options()
+        };
+        assert!(ids(&db, "starlink", &polar).is_empty());
+    }
+
+    #[tokio::test]
+    async fn filters_by_launch_date_and_reentry() {
+        let db = database().await;
+
+        let recent = SearchOptions {
+            launched: Bounds {
+                min: NaiveDate::from_ymd_opt(2019, 11, 11),
+                max: None,
+            },
+            ..options()
+        };
+        assert_eq!(ids(&db, "starlink", &recent), [44713, 44714]);
+        assert!(ids(&db, "hst", &recent).is_empty());
+
+        let reentering = SearchOptions {
+            reentry_soon: true,
+            ..options()
+        };
+        assert_eq!(ids(&db, "cz-2c", &reentering), [28000]);
+        assert!(ids(&db, "starlink", &reentering).is_empty());
+    }
+
+    #[test]
+    fn fold_drops_diacritics_and_punctuation() {
+        assert_eq!(fold("SATÉLITE ÁGUA"), "satelite agua");
+        assert_eq!(fold("Ørsted-1"), "orsted 1");
+        assert_eq!(fold("  Łódź  (Đakovo) "), "lodz dakovo");
+    }
+
+    #[tokio::test]
+    async fn accented_names_match_plain_queries() {
+        let db = database().await;
+
+        assert_eq!(ids(&db, "satelite agua", &options()), [43000]);
+        assert_eq!(ids(&db, "SATÉLITE", &options()), [43000]);
+        assert_eq!(ids(&db, "sátelite", &options()), [43000]);
+    }
+
+    #[tokio::test]
+    async fn exact_id_and_designator_matches_skip_the_filters() {
+        let db = database().await;
+
+        assert_eq!(ids(&db, "25544", &options()), [25544]);
+        assert_eq!(ids(&db, "1998-067a", &options()), [25544]);
+        assert!(ids(&db, "44720", &options()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn matches_are_ranked_by_score_then_newest_first() {
+        let satcat = [
+            (50, "STAR 2"),
+            (100, "STARLINK-2"),
+            (200, "STAR"),
+            (300, "NORTHSTAR"),
+            (400, "STARLINK-1"),
+        ]
+        .map(|(id, name)| {
+            format!(
+                r#"{{"NORAD_CAT_ID":"{id}","OBJECT_ID":"2000-{id:03}A","OBJECT_TYPE":"PAYLOAD","OBJECT_NAME":"{name}","LAUNCH":"2000-01-01","DECAY":null}}"#
+            )
+        })
+        .join(",");
+        let client = MockClient::new().respond(QUERY_PATH, format!("[{satcat}]"));
+        let db = SatelliteDatabase::new(Arc::new(client), Duration::from_secs(60));
+        db.update().await.unwrap();
+
+        let ranked = |query| {
+            db.search(query, &options())
+                .results
+                .iter()
+                .map(SearchResult::id)
+                .collect::<Vec<_>>()
+        };
+
+        // Equal scores put the higher IDs first, but never ahead of a better score
+        assert_eq!(ranked("star"), [400, 200, 100, 50, 300]);
+        assert_eq!(ranked("starlink"), [400, 100]);
+
+        // Exact IDs and designators come before anything the fuzzy search finds
+        assert_eq!(ranked("300"), [300]);
+        assert_eq!(ranked("2000-050A"), [50]);
+    }
+
+    #[tokio::test]
+    async fn suggestions_leave_out_the_same_types_for_prefixes_and_fuzzy_matches() {
+        let db = database().await;
+        let suggested = |query| {
+            let mut ids = db.suggest(query).iter().map(|s| s.id).collect::<Vec<_>>();
+            ids.sort_unstable();
+            ids
+        };
+
+        assert_eq!(suggested("STARL"), [44713, 44714]);
+        assert_eq!(suggested("TARLINK"), [44713, 44714]);
+
+        // Neither debris nor unknown objects, no matter how the name is typed
+        for query in ["COSMOS", "OSMOS 2251", "OBJEC", "BJECT C"] {
+            assert!(suggested(query).is_empty(), "{query}");
         }
     }
+
+    #[test]
+    fn regimes_follow_the_shape_of_the_orbit() {
+        let regime = |period: f64, inclination: f64, apogee: f64, perigee: f64| {
+            let satellite = format!(
+                r#"{{"NORAD_CAT_ID":"1","OBJECT_TYPE":"PAYLOAD","OBJECT_NAME":"TEST","LAUNCH":"2000-01-01","DECAY":null,"PERIOD":"{period}","INCLINATION":"{inclination}","APOGEE":"{apogee}","PERIGEE":"{perigee}"}}"#
+            );
+            serde_json::from_str::<Satellite>(&satellite)
+                .unwrap()
+                .regime()
+        };
+
+        assert_eq!(regime(92.9, 51.6, 420.0, 415.0), Some(Regime::Leo));
+        assert_eq!(regime(717.9, 55.0, 20200.0, 20180.0), Some(Regime::Meo));
+        assert_eq!(regime(1436.1, 0.1, 35796.0, 35777.0), Some(Regime::Geo));
+        // Graveyard orbits lie above geostationary altitude, but are just as circular
+        assert_eq!(regime(1450.3, 1.2, 36100.0, 36080.0), Some(Regime::Geo));
+        // Molniya and geostationary transfer orbits
+        assert_eq!(regime(717.7, 63.4, 39700.0, 600.0), Some(Regime::Heo));
+        assert_eq!(regime(630.0, 27.0, 35800.0, 250.0), Some(Regime::Heo));
+    }
 }
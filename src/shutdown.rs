@@ -0,0 +1,76 @@
+use crate::locks::Recover;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::error;
+
+/// Resolves once the process is asked to stop, either by Ctrl+C or by a SIGTERM
+pub async fn signal() {
+    tokio::select! {
+        _ = ctrl_c() => {}
+        _ = terminate() => {}
+    }
+}
+
+async fn ctrl_c() {
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        error!(error = %e, "Failed to listen for Ctrl+C");
+        std::future::pending::<()>().await;
+    }
+}
+
+/// What Docker and systemd send to stop the process
+#[cfg(unix)]
+async fn terminate() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut sigterm) => {
+            sigterm.recv().await;
+        }
+        Err(e) => {
+            error!(error = %e, "Failed to listen for SIGTERM");
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn terminate() {
+    std::future::pending::<()>().await;
+}
+
+/// Forces the process to exit if shutting down takes longer than the timeout
+#[derive(Clone)]
+pub struct Watchdog {
+    /// What the shutdown is currently busy with, reported if it gets cut short
+    phase: Arc<Mutex<&'static str>>,
+}
+
+impl Watchdog {
+    pub fn arm(timeout: Duration) -> Self {
+        let phase = Arc::new(Mutex::new("draining connections"));
+        let watchdog = Self {
+            phase: phase.clone(),
+        };
+
+        tokio::spawn(async move {
+            signal().await;
+            tokio::time::sleep(timeout).await;
+
+            let phase = *phase.lock_or_recover();
+            error!(
+                ?timeout,
+                phase, "Shutdown did not finish in time, skipping the rest"
+            );
+            std::process::exit(1);
+        });
+
+        watchdog
+    }
+
+    pub fn enter(&self, phase: &'static str) {
+        *self.phase.lock_or_recover() = phase;
+    }
+}
@@ -0,0 +1,164 @@
+use crate::gp::GpRecord;
+use chrono::{Datelike, Timelike};
+
+// Alpha-5 skips I and O to avoid confusion with 1 and 0
+const ALPHA5_LETTERS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ";
+
+/// Modulo-10 checksum over the first 68 characters of a TLE line
+pub fn tle_checksum(line: &str) -> u8 {
+    let sum: u32 = line
+        .chars()
+        .take(68)
+        .map(|c| match c {
+            '-' => 1,
+            c => c.to_digit(10).unwrap_or(0),
+        })
+        .sum();
+
+    (sum % 10) as u8
+}
+
+/// Checks the trailing checksum digit of a TLE line, returning the expected value on mismatch
+pub fn verify_checksum(line: &str) -> Result<(), u8> {
+    let expected = tle_checksum(line);
+    let actual = line.chars().nth(68).and_then(|c| c.to_digit(10));
+
+    match actual {
+        Some(actual) if actual == expected as u32 => Ok(()),
+        _ => Err(expected),
+    }
+}
+
+/// Renders the two element lines of a TLE from the GP record
+pub fn render(record: &GpRecord) -> [String; 2] {
+    let satnum = alpha5(record.norad_cat_id);
+    let epoch = record.epoch;
+    let day_fraction =
+        (epoch.num_seconds_from_midnight() as f64 + epoch.nanosecond() as f64 / 1e9) / 86400.0;
+
+    let line1 = format!(
+        "1 {satnum}{classification} {designator:<8} {year:02}{day:012.8} {ndot} {nddot} {bstar} {ephemeris} {set:>4}",
+        classification = record.classification_type,
+        designator = international_designator(record.object_id.as_deref()),
+        year = epoch.year() % 100,
+        day = epoch.ordinal() as f64 + day_fraction,
+        ndot = decimal_point_assumed_fraction(record.mean_motion_dot),
+        nddot = exponential(record.mean_motion_ddot),
+        bstar = exponential(record.bstar),
+        ephemeris = record.ephemeris_type,
+        set = record.element_set_number % 10000,
+    );
+
+    let line2 = format!(
+        "2 {satnum} {inclination:>8.4} {raan:>8.4} {eccentricity:07} {argp:>8.4} {anomaly:>8.4} {motion:>11.8}{rev:>5}",
+        inclination = record.inclination,
+        raan = record.ra_of_asc_node,
+        eccentricity = (record.eccentricity * 1e7).round() as u32,
+        argp = record.arg_of_pericenter,
+        anomaly = record.mean_anomaly,
+        motion = record.mean_motion,
+        rev = record.rev_at_epoch % 100000,
+    );
+
+    [line1, line2].map(|line| {
+        let checksum = tle_checksum(&line);
+        format!("{line}{checksum}")
+    })
+}
+
+/// Renders a three-line element set, prefixing the element lines with the object name
+pub fn render_3le(record: &GpRecord) -> String {
+    let [line1, line2] = render(record);
+    let name = record.object_name.as_deref().unwrap_or_default();
+    format!("{name}\n{line1}\n{line2}\n")
+}
+
+fn alpha5(id: usize) -> String {
+    match id {
+        0..=99999 => format!("{id:05}"),
+        _ => {
+            let letter = ALPHA5_LETTERS
+                .get(id / 10000 - 10)
+                .map_or('?', |l| *l as char);
+            format!("{letter}{:04}", id % 10000)
+        }
+    }
+}
+
+/// Turns a COSPAR ID like `1998-067A` into the TLE form `98067A`
+fn international_designator(object_id: Option<&str>) -> String {
+    match object_id.and_then(|id| id.split_once('-')) {
+        Some((year, rest)) if year.len() == 4 => format!("{}{rest}", &year[2..]),
+        _ => String::new(),
+    }
+}
+
+/// Formats e.g. `0.00011716` as ` .00011716`
+fn decimal_point_assumed_fraction(value: f64) -> String {
+    let sign = if value < 0.0 { '-' } else { ' ' };
+    let digits = format!("{:.8}", value.abs());
+    format!("{sign}{}", digits.trim_start_matches('0'))
+}
+
+/// Formats e.g. `0.00021111` as ` 21111-3` (mantissa with an assumed leading decimal point)
+fn exponential(value: f64) -> String {
+    if value == 0.0 {
+        return " 00000-0".into();
+    }
+
+    let sign = if value < 0.0 { '-' } else { ' ' };
+    let mut exponent = value.abs().log10().floor() as i32 + 1;
+    let mut mantissa = (value.abs() / 10f64.powi(exponent) * 1e5).round() as u32;
+
+    // Rounding can carry over into another digit
+    if mantissa >= 100000 {
+        mantissa /= 10;
+        exponent += 1;
+    }
+
+    let exponent_sign = if exponent < 0 { '-' } else { '+' };
+    format!("{sign}{mantissa:05}{exponent_sign}{}", exponent.abs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ISS: &str = include_str!("fixtures/iss_gp.json");
+    const ISS_LINE1: &str = "1 25544U 98067A   08264.51782528 -.00002182  00000-0 -11606-4 0  2927";
+    const ISS_LINE2: &str = "2 25544  51.6416 247.4627 0006703 130.5360 325.0288 15.72125391563537";
+
+    fn iss() -> GpRecord {
+        let mut records: Vec<GpRecord> = serde_json::from_str(ISS).unwrap();
+        records.remove(0)
+    }
+
+    #[test]
+    fn checksum_of_reference_lines() {
+        assert_eq!(tle_checksum(ISS_LINE1), 7);
+        assert_eq!(tle_checksum(ISS_LINE2), 7);
+    }
+
+    #[test]
+    fn verify_checksum_reports_expected_digit() {
+        assert_eq!(verify_checksum(ISS_LINE1), Ok(()));
+        assert_eq!(verify_checksum(ISS_LINE2), Ok(()));
+
+        let corrupted = ISS_LINE1.replace("2927", "2921");
+        assert_eq!(verify_checksum(&corrupted), Err(7));
+        assert_eq!(verify_checksum(&ISS_LINE1[..68]), Err(7));
+    }
+
+    #[test]
+    fn render_matches_reference_lines() {
+        assert_eq!(render(&iss()), [ISS_LINE1, ISS_LINE2]);
+    }
+
+    #[test]
+    fn render_3le_prefixes_name() {
+        assert_eq!(
+            render_3le(&iss()),
+            format!("ISS (ZARYA)\n{ISS_LINE1}\n{ISS_LINE2}\n")
+        );
+    }
+}
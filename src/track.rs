@@ -0,0 +1,82 @@
+use crate::{
+    cache::CachedData, gp::GpRecord, perturbation::PerturbationCache, propagation, NoradId,
+};
+use chrono::{DateTime, Utc};
+use futures_util::{SinkExt, StreamExt};
+use poem::web::websocket::{Message, WebSocketStream};
+use propagation::Geodetic;
+use serde::Serialize;
+use std::time::Duration;
+use tokio::time::{Instant, MissedTickBehavior};
+use tracing::{debug, warn};
+
+#[derive(Serialize, Debug)]
+struct Position {
+    norad_id: NoradId,
+    time: DateTime<Utc>,
+    #[serde(flatten)]
+    geodetic: Geodetic,
+}
+
+/// Pushes the position of the object every `interval` until the client goes away
+///
+/// The elements are pulled from the cache again once they are due for a refresh, so long-lived
+/// connections pick up new element sets like every other request.
+pub async fn stream_positions(
+    mut socket: WebSocketStream,
+    cache: PerturbationCache,
+    id: NoradId,
+    mut cached: CachedData<GpRecord>,
+    interval: Duration,
+) {
+    let mut elements = cached.data.to_elements();
+    let mut refresh_at = Instant::now() + cached.ttl();
+
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            message = socket.next() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                // Nothing the client says matters to us
+                Some(Ok(_)) => continue,
+            },
+        }
+
+        if Instant::now() >= refresh_at {
+            match cache.get_or_fetch(id, None).await {
+                Ok(refreshed) => {
+                    cached = refreshed;
+                    elements = cached.data.to_elements();
+                }
+                // Keep going with what we have, it's only slightly outdated
+                Err(e) => warn!(norad_id = id, error = %e, "Failed to refresh tracked elements"),
+            }
+
+            // Stale data has no time left, so don't retry on every single tick
+            refresh_at = Instant::now() + cached.ttl().max(interval);
+        }
+
+        let time = Utc::now();
+        let state = propagation::propagate(&elements, time).map_err(|e| e.to_string());
+        let position = match state {
+            Ok(state) => Position {
+                norad_id: id,
+                time,
+                geodetic: state.geodetic(),
+            },
+            Err(e) => {
+                debug!(norad_id = id, error = %e, "Stopped tracking, propagation failed");
+                let _ = socket.send(Message::Close(None)).await;
+                break;
+            }
+        };
+
+        let message = serde_json::to_string(&position).unwrap_or_default();
+        if socket.send(Message::Text(message)).await.is_err() {
+            break;
+        }
+    }
+}